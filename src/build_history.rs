@@ -0,0 +1,454 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::prelude::*;
+use time::Duration;
+
+use crate::build_info_manager::BuildKey;
+
+pub struct BuildRecord {
+    pub monitor_name: String,
+    pub build_key: BuildKey,
+    pub pipeline_name: String,
+    pub build_step: String,
+    pub failed: bool,
+    pub time: DateTime<Utc>,
+    pub commit_time: Option<DateTime<Utc>>,
+}
+
+impl BuildRecord {
+    pub fn stage_key(&self) -> String {
+        format!("{}/{}", self.pipeline_name, self.build_step)
+    }
+}
+
+pub struct LeadTime {
+    pub monitor_name: String,
+    pub build_key: BuildKey,
+    pub lead_time: Duration,
+    pub time: DateTime<Utc>,
+}
+
+pub struct LeadTimePercentiles {
+    pub count: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+//Only kept in memory, so after a restart it has nothing from before started
+pub struct BuildHistory {
+    records: Vec<BuildRecord>,
+    lead_times: Vec<LeadTime>,
+    started: DateTime<Utc>,
+}
+
+pub struct DigestSummary {
+    pub build_count: usize,
+    pub passed_count: usize,
+    //Only from builds that got to their monitor's final stage
+    pub mean_lead_time: Option<Duration>,
+    //When the history started, if that was partway through the digest's period
+    pub history_start: Option<DateTime<Utc>>,
+    pub slowest_stages: Vec<(String, Duration)>,
+    pub flakiest_stages: Vec<(String, usize)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StageFlakiness {
+    pub stage_key: String,
+    pub flaky_builds: usize,
+    pub builds: usize,
+}
+
+impl StageFlakiness {
+    pub fn score(&self) -> f64 {
+        self.flaky_builds as f64 / self.builds as f64
+    }
+}
+
+const TOP_STAGE_COUNT: usize = 3;
+
+impl BuildHistory {
+    pub fn new() -> BuildHistory {
+        BuildHistory { records: vec![], lead_times: vec![], started: Utc::now() }
+    }
+
+    pub fn add(&mut self, record: BuildRecord) {
+        self.records.push(record);
+    }
+
+    pub fn last_record_time(&self, monitor_name: &str, build_key: &BuildKey) -> Option<DateTime<Utc>> {
+        self.records.iter().rev()
+            .find(|r| r.monitor_name == monitor_name && r.build_key == *build_key)
+            .map(|r| r.time)
+    }
+
+    pub fn first_record_time(&self, monitor_name: &str, build_key: &BuildKey) -> Option<DateTime<Utc>> {
+        self.records.iter()
+            .find(|r| r.monitor_name == monitor_name && r.build_key == *build_key)
+            .map(|r| r.time)
+    }
+
+    //Records kept under a fallback key while GoCD couldn't be reached move to the build's real key once it's known,
+    //picking up the commit time they went without
+    pub fn rekey(&mut self, monitor_name: &str, fallback_key: &BuildKey, build_key: &BuildKey,
+                 commit_time: Option<DateTime<Utc>>) {
+        for record in self.records.iter_mut().filter(|r| r.monitor_name == monitor_name && r.build_key == *fallback_key) {
+            record.build_key = build_key.clone();
+            record.commit_time = record.commit_time.or(commit_time);
+        }
+        for lead_time in self.lead_times.iter_mut()
+            .filter(|l| l.monitor_name == monitor_name && l.build_key == *fallback_key) {
+            lead_time.build_key = build_key.clone();
+        }
+    }
+
+    pub fn fallback_keys(&self) -> Vec<(String, BuildKey)> {
+        let mut fallback_keys: Vec<(String, BuildKey)> = self.records.iter()
+            .filter(|r| r.build_key.is_fallback())
+            .map(|r| (r.monitor_name.clone(), r.build_key.clone()))
+            .collect();
+        fallback_keys.sort();
+        fallback_keys.dedup();
+        fallback_keys
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn prune(&mut self, max_age: Duration) {
+        self.records.retain(|record| Utc::now().signed_duration_since(record.time) < max_age);
+        self.lead_times.retain(|lead_time| Utc::now().signed_duration_since(lead_time.time) < max_age);
+    }
+
+    //Lead time runs from the commit, or from when we first heard about the build if GoCD didn't tell us the commit
+    //time. Only the first time a build's final stage passes counts, so reruns of a deploy don't stretch it out.
+    pub fn record_lead_time(&mut self, monitor_name: &str, build_key: &BuildKey, commit_time: Option<DateTime<Utc>>)
+    -> Option<Duration> {
+        if let Some(lead_time) = self.lead_time_for_build(monitor_name, build_key) {
+            return Some(lead_time);
+        }
+        let start_time = commit_time.or_else(|| self.first_record_time(monitor_name, build_key))?;
+        let lead_time = Utc::now().signed_duration_since(start_time);
+        self.lead_times.push(LeadTime {
+            monitor_name: monitor_name.to_string(),
+            build_key: build_key.clone(),
+            lead_time,
+            time: Utc::now(),
+        });
+        Some(lead_time)
+    }
+
+    pub fn lead_time_for_build(&self, monitor_name: &str, build_key: &BuildKey) -> Option<Duration> {
+        self.lead_times.iter()
+            .find(|l| l.monitor_name == monitor_name && l.build_key == *build_key)
+            .map(|l| l.lead_time)
+    }
+
+    pub fn lead_time_percentiles(&self, monitor_name: &str) -> Option<LeadTimePercentiles> {
+        let mut lead_times: Vec<Duration> = self.lead_times.iter()
+            .filter(|l| l.monitor_name == monitor_name)
+            .map(|l| l.lead_time)
+            .collect();
+        if lead_times.is_empty() {
+            return None;
+        }
+        lead_times.sort();
+        let nearest_rank = |percentile: usize| lead_times[(lead_times.len() * percentile - 1) / 100];
+        Some(LeadTimePercentiles {
+            count: lead_times.len(),
+            p50: nearest_rank(50),
+            p90: nearest_rank(90),
+            p99: nearest_rank(99),
+        })
+    }
+
+    //Records arrive in time order, so the records of a build are already sorted within each group
+    fn builds_since(&self, monitor_name: &str, since: DateTime<Utc>) -> BTreeMap<&BuildKey, Vec<&BuildRecord>> {
+        let mut builds: BTreeMap<&BuildKey, Vec<&BuildRecord>> = BTreeMap::new();
+        for record in self.records.iter().filter(|r| r.monitor_name == monitor_name && r.time >= since) {
+            builds.entry(&record.build_key).or_default().push(record);
+        }
+        builds
+    }
+
+    pub fn flaky_stages_for_build(&self, monitor_name: &str, build_key: &BuildKey) -> Vec<String> {
+        let records: Vec<&BuildRecord> = self.records.iter()
+            .filter(|r| r.monitor_name == monitor_name && r.build_key == *build_key)
+            .collect();
+        flipped_stages(&records)
+    }
+
+    //A stage's score is the share of the builds that ran it where it had to be rerun to pass
+    pub fn flakiness_scores(&self, monitor_name: Option<&str>) -> Vec<StageFlakiness> {
+        let mut builds: BTreeMap<(&str, &BuildKey), Vec<&BuildRecord>> = BTreeMap::new();
+        for record in self.records.iter().filter(|r| monitor_name.is_none() || monitor_name == Some(&r.monitor_name)) {
+            builds.entry((&record.monitor_name, &record.build_key)).or_default().push(record);
+        }
+        let mut stages: HashMap<String, StageFlakiness> = HashMap::new();
+        for records in builds.values() {
+            let mut stage_keys: Vec<String> = records.iter().map(|r| r.stage_key()).collect();
+            stage_keys.sort();
+            stage_keys.dedup();
+            for stage_key in stage_keys {
+                stages.entry(stage_key.clone())
+                    .or_insert(StageFlakiness { stage_key, flaky_builds: 0, builds: 0 })
+                    .builds += 1;
+            }
+            for stage_key in flipped_stages(records) {
+                if let Some(stage) = stages.get_mut(&stage_key) {
+                    stage.flaky_builds += 1;
+                }
+            }
+        }
+        let mut scores: Vec<StageFlakiness> = stages.into_iter()
+            .filter(|(_, stage)| stage.flaky_builds > 0)
+            .map(|(_, stage)| stage)
+            .collect();
+        scores.sort_by(|a, b| b.score().partial_cmp(&a.score()).unwrap()
+            .then_with(|| b.flaky_builds.cmp(&a.flaky_builds))
+            .then_with(|| a.stage_key.cmp(&b.stage_key)));
+        scores
+    }
+
+    pub fn summarize(&self, monitor_name: &str, since: DateTime<Utc>) -> DigestSummary {
+        let builds = self.builds_since(monitor_name, since);
+        let mut passed_count = 0;
+        let mut stage_durations: HashMap<String, (Duration, i32)> = HashMap::new();
+        let mut stage_flips: HashMap<String, usize> = HashMap::new();
+
+        for records in builds.values() {
+            for stage_key in flipped_stages(records) {
+                *stage_flips.entry(stage_key).or_insert(0) += 1;
+            }
+            let mut latest_results: HashMap<String, bool> = HashMap::new();
+            for record in records {
+                latest_results.insert(record.stage_key(), record.failed);
+            }
+
+            //GoCD only tells us when a stage finishes, so a stage's duration is approximated by the time since
+            //the previous notification for the same build
+            for pair in records.windows(2) {
+                let duration = pair[1].time.signed_duration_since(pair[0].time);
+                let stage_total = stage_durations.entry(pair[1].stage_key()).or_insert((Duration::zero(), 0));
+                stage_total.0 = stage_total.0 + duration;
+                stage_total.1 += 1;
+            }
+
+            if latest_results.values().all(|failed| !failed) {
+                passed_count += 1;
+            }
+        }
+
+        //A build whose stages have all passed so far may still be on its way out, so only builds that reached the
+        //monitor's final stage have a lead time
+        let lead_times: Vec<Duration> = self.lead_times.iter()
+            .filter(|l| l.monitor_name == monitor_name && l.time >= since)
+            .map(|l| l.lead_time)
+            .collect();
+        let mean_lead_time = if lead_times.is_empty() {
+            None
+        } else {
+            let total = lead_times.iter().fold(Duration::zero(), |acc, lead_time| acc + *lead_time);
+            Some(total / lead_times.len() as i32)
+        };
+
+        let mut slowest_stages: Vec<(String, Duration)> = stage_durations.into_iter()
+            .map(|(stage_key, (total, count))| (stage_key, total / count))
+            .collect();
+        slowest_stages.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        slowest_stages.truncate(TOP_STAGE_COUNT);
+
+        let mut flakiest_stages: Vec<(String, usize)> = stage_flips.into_iter().collect();
+        flakiest_stages.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        flakiest_stages.truncate(TOP_STAGE_COUNT);
+
+        DigestSummary {
+            build_count: builds.len(),
+            passed_count,
+            mean_lead_time,
+            history_start: Some(self.started).filter(|started| *started > since),
+            slowest_stages,
+            flakiest_stages,
+        }
+    }
+}
+
+//Stages that failed and then passed on a rerun within the same build, in the order they were rerun
+fn flipped_stages(records: &[&BuildRecord]) -> Vec<String> {
+    let mut latest_results: HashMap<String, bool> = HashMap::new();
+    let mut flipped: Vec<String> = vec![];
+    for record in records {
+        let stage_key = record.stage_key();
+        if let Some(true) = latest_results.get(&stage_key) {
+            if !record.failed && !flipped.contains(&stage_key) {
+                flipped.push(stage_key.clone());
+            }
+        }
+        latest_results.insert(stage_key, record.failed);
+    }
+    flipped
+}
+
+impl DigestSummary {
+    pub fn to_message(&self, monitor_name: &str, period_name: &str) -> String {
+        let pass_rate = self.passed_count * 100 / self.build_count.max(1);
+        let mut lines = vec![
+            format!("*{} build digest for {}*", period_name, monitor_name),
+            format!("Builds: {}, passed: {} ({}%)", self.build_count, self.passed_count, pass_rate),
+        ];
+        if let Some(lead_time) = self.mean_lead_time {
+            lines.push(format!("Mean time from commit to the final stage: {}", format_duration(lead_time)));
+        }
+        if !self.slowest_stages.is_empty() {
+            let stages: Vec<String> = self.slowest_stages.iter()
+                .map(|(stage_key, duration)| format!("{} ({})", stage_key, format_duration(*duration)))
+                .collect();
+            lines.push(format!("Slowest stages: {}", stages.join(", ")));
+        }
+        if !self.flakiest_stages.is_empty() {
+            let stages: Vec<String> = self.flakiest_stages.iter()
+                .map(|(stage_key, flips)| format!("{} ({} flaky runs)", stage_key, flips))
+                .collect();
+            lines.push(format!("Flakiest stages: {}", stages.join(", ")));
+        }
+        if let Some(history_start) = self.history_start {
+            lines.push(format!("_Build history isn't kept across restarts, so this only covers builds since {}_",
+                history_start.format("%a %b %-d %H:%M UTC")));
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    if duration.num_hours() > 0 {
+        format!("{}h {}m", duration.num_hours(), duration.num_minutes() % 60)
+    }
+    else if duration.num_minutes() > 0 {
+        format!("{}m", duration.num_minutes())
+    }
+    else {
+        format!("{}s", duration.num_seconds())
+    }
+}
+
+#[cfg(test)]
+mod history_tests {
+    use super::*;
+
+    fn record(now: DateTime<Utc>, modification_id: u64, build_step: &str, failed: bool, minutes_ago: i64) -> BuildRecord {
+        BuildRecord {
+            monitor_name: "test".to_string(),
+            build_key: BuildKey::Modification(modification_id),
+            pipeline_name: "Test_Pipeline".to_string(),
+            build_step: build_step.to_string(),
+            failed,
+            time: now - Duration::minutes(minutes_ago),
+            commit_time: Some(now - Duration::minutes(100)),
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let now = Utc::now();
+        let mut history = BuildHistory::new();
+        history.add(record(now, 1, "Build", false, 90));
+        history.add(record(now, 1, "Test", true, 60));
+        history.add(record(now, 1, "Test", false, 50));
+        history.add(record(now, 1, "Deploy", false, 40));
+        history.add(record(now, 2, "Build", false, 30));
+        history.add(record(now, 2, "Test", true, 10));
+        history.add(record(now, 3, "Build", false, 60 * 48));
+
+        history.started = now - Duration::days(3);
+
+        let summary = history.summarize("test", now - Duration::days(1));
+        assert_eq!(summary.build_count, 2);
+        assert_eq!(summary.passed_count, 1);
+        assert_eq!(summary.mean_lead_time, None);
+        assert_eq!(summary.history_start, None);
+        assert_eq!(summary.slowest_stages.first().unwrap().0, "Test_Pipeline/Test");
+        assert_eq!(summary.flakiest_stages, vec![("Test_Pipeline/Test".to_string(), 1)]);
+        assert!(!summary.to_message("test", "Daily").contains("from commit"));
+
+        history.record_lead_time("test", &BuildKey::Modification(1), Some(now - Duration::minutes(100)));
+        let summary = history.summarize("test", now - Duration::days(1));
+        assert_eq!(summary.mean_lead_time.unwrap().num_minutes(), 100);
+        assert!(summary.to_message("test", "Daily").contains("\nMean time from commit to the final stage: 1h 40m"));
+
+        history.started = now - Duration::hours(2);
+        let summary = history.summarize("test", now - Duration::weeks(1));
+        assert_eq!(summary.history_start, Some(now - Duration::hours(2)));
+        assert!(summary.to_message("test", "Weekly").ends_with(&format!(
+            "_Build history isn't kept across restarts, so this only covers builds since {}_",
+            (now - Duration::hours(2)).format("%a %b %-d %H:%M UTC"))));
+    }
+
+    #[test]
+    fn test_flakiness() {
+        let now = Utc::now();
+        let mut history = BuildHistory::new();
+        history.add(record(now, 1, "Test", true, 60));
+        history.add(record(now, 1, "Test", false, 50));
+        history.add(record(now, 2, "Test", false, 40));
+        history.add(record(now, 2, "Deploy", true, 30));
+        history.add(record(now, 2, "Deploy", true, 20));
+
+        assert_eq!(history.flaky_stages_for_build("test", &BuildKey::Modification(1)),
+            vec!["Test_Pipeline/Test".to_string()]);
+        assert!(history.flaky_stages_for_build("test", &BuildKey::Modification(2)).is_empty());
+        assert_eq!(history.flakiness_scores(Some("test")), vec![
+            StageFlakiness { stage_key: "Test_Pipeline/Test".to_string(), flaky_builds: 1, builds: 2 }
+        ]);
+        assert!(history.flakiness_scores(Some("other")).is_empty());
+    }
+
+    #[test]
+    fn test_lead_times() {
+        let now = Utc::now();
+        let mut history = BuildHistory::new();
+        history.add(record(now, 1, "Build", false, 90));
+        history.add(record(now, 1, "Deploy", false, 0));
+        let lead_time = history.record_lead_time("test", &BuildKey::Modification(1), Some(now - Duration::minutes(100)))
+            .unwrap();
+        assert_eq!(lead_time.num_minutes(), 100);
+        assert_eq!(history.record_lead_time("test", &BuildKey::Modification(1), None).unwrap(), lead_time);
+        assert_eq!(history.record_lead_time("test", &BuildKey::Modification(2), None), None);
+
+        let percentiles = history.lead_time_percentiles("test").unwrap();
+        assert_eq!(percentiles.count, 1);
+        assert_eq!(percentiles.p99, lead_time);
+        assert!(history.lead_time_percentiles("other").is_none());
+    }
+
+    #[test]
+    fn test_rekey() {
+        let now = Utc::now();
+        let fallback_key = BuildKey::Pipeline { pipeline_name: "Test_Pipeline".to_string(), counter: 12 };
+        let fallback_record = |build_step, failed, minutes_ago| BuildRecord {
+            build_key: fallback_key.clone(),
+            commit_time: None,
+            ..record(now, 0, build_step, failed, minutes_ago)
+        };
+        let mut history = BuildHistory::new();
+        history.add(record(now, 1955, "Build", false, 90));
+        history.add(fallback_record("Test", true, 60));
+        history.add(fallback_record("Test", false, 50));
+        assert_eq!(history.fallback_keys(), vec![("test".to_string(), fallback_key.clone())]);
+
+        history.rekey("test", &fallback_key, &BuildKey::Modification(1955), Some(now - Duration::minutes(100)));
+        assert!(history.fallback_keys().is_empty());
+        assert_eq!(history.first_record_time("test", &BuildKey::Modification(1955)), Some(now - Duration::minutes(90)));
+        assert_eq!(history.flaky_stages_for_build("test", &BuildKey::Modification(1955)),
+            vec!["Test_Pipeline/Test".to_string()]);
+        assert!(history.records.iter().all(|r| r.commit_time == Some(now - Duration::minutes(100))));
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::seconds(42)), "42s");
+        assert_eq!(format_duration(Duration::minutes(42)), "42m");
+        assert_eq!(format_duration(Duration::minutes(83)), "1h 23m");
+    }
+}
@@ -1,81 +1,1370 @@
-use std::sync::{Mutex, RwLock};
-use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::collections::hash_map::Entry;
+use std::fmt::Debug;
 
-use slack_api::chat::{post_message, PostMessageRequest, update, UpdateRequest};
+use slack_api::chat::{post_message, PostMessageRequest, update, UpdateRequest, delete, DeleteRequest};
 use slack_api::requests::{default_client, Client};
+use slack_api::auth;
+use slack_api::pins;
+use serde_json::{Value, json};
 use chrono::prelude::*;
+use chrono_tz::Tz;
 use time::Duration;
+use serde_derive::Deserialize;
 
-use crate::gocd::GoCDInfo;
+use crate::gocd::{self, GoCDCredentials, GoCDError, GoCDInfo, HistoryItem, Stage};
+use crate::build_history::{BuildHistory, BuildRecord, StageFlakiness, LeadTimePercentiles, format_duration};
+use crate::scheduler::DigestPeriod;
+use crate::metrics::{Metrics, render_lead_times};
+use crate::health::{ConfigLoad, GoCDAuthCheck, HealthTracker, HealthStatus};
+use crate::template::MessageTemplates;
+use crate::store::BotStore;
+use crate::commands::{BuildCommand, COMMAND_HELP};
+use crate::console_log::LogExcerptPolicy;
+use crate::junit::{ReportParser, TestReportPolicy, TestSummary};
+use crate::parser::{StageDetails, StageEvent, StageResult};
+use crate::user_directory::UserDirectory;
+use crate::monitor_config::{MonitorConfig, QuietHoursConfig, RetentionConfig, StallPolicyConfig};
 
 pub trait AcceptBuildInfo {
-    fn new_build_message(&self, stage_name: &str, build_num: u64, build_step: &str, pass_fail: &str);
+    fn new_build_message(&self, event: &StageEvent);
+    fn set_author_dm_opt_out(&self, user_id: &str, opted_out: bool);
 }
 
 pub struct BuildInfoManager {
     message_index: Mutex<HashMap<BuildInfoIndex, BuildInfoEntry>>,
     slack_instance_token: String,
     slack_client: Client,
-    last_cleanout_time: RwLock<DateTime<Utc>>,
     info_monitors: Vec<BuildInfoMonitor>,
-    gocd_talker: GoCDInfo
+    gocd_talker: GoCDInfo,
+    build_history: Mutex<BuildHistory>,
+    metrics: Metrics,
+    health: HealthTracker,
+    pinned_statuses: Mutex<HashMap<String, PinnedStatusMessage>>,
+    store: BotStore,
+    user_directory: UserDirectory,
+    quiet_hour_buffers: Mutex<HashMap<String, BTreeMap<BuildKey, String>>>,
+    //Builds whose authors have already had a DM about a failure, and when
+    authors_notified: Mutex<HashMap<BuildInfoIndex, DateTime<Utc>>>,
+    //Stage events wait here for the event worker, since handling one means calls to GoCD that can outlast the 3
+    //seconds Slack gives us to acknowledge the event
+    event_sender: Mutex<Sender<StageEvent>>,
+    event_receiver: Mutex<Option<Receiver<StageEvent>>>,
+    //Events sent to the event worker that it hasn't finished with, since a channel can't say how much is in it
+    pending_events: AtomicUsize,
+    //The ids of events Slack sent us recently, oldest first, so a retried delivery isn't handled twice
+    recent_event_ids: Mutex<VecDeque<String>>,
+    report_parser: ReportParser,
+    //How main got on loading each piece of config at startup
+    config_status: Vec<(&'static str, ConfigLoad)>,
 }
 
 struct BuildInfoEntry {
     failed: bool,
+    completed: bool,
+    stalled: bool,
+    superseded: bool,
+    passed_stages: Vec<String>,
     slack_timestamp: String,
     last_update_time: DateTime<Utc>,
+    last_stage: String,
+    message_text: String,
+}
+
+struct BuildUpdate {
+    message_text: String,
+    thread_reply: Option<String>,
+    stage_key: String,
+    stage_label: String,
+    result: StageResult,
+    completed: bool,
+}
+
+//What main has read and checked from a monitor's config, for BuildInfoManager::new to apply over its defaults
+#[derive(Default)]
+pub struct MonitorSettings {
+    pub templates: Option<MessageTemplates>,
+    pub grouping: Option<Grouping>,
+    pub value_stream_fan_in: Option<bool>,
+    quiet_hours: Option<QuietHours>,
+    final_stage: Option<FinalStage>,
+    stall_policy: Option<StallPolicy>,
+    retention: Option<RetentionPolicy>,
+    per_build_messages: Option<bool>,
+    pinned_status: Option<PinnedStatus>,
+    threaded_details: Option<bool>,
+    log_excerpt: Option<LogExcerptPolicy>,
+    test_reports: Option<TestReportPolicy>,
+}
+
+impl MonitorSettings {
+    //Anything in the config that can only be checked by trying it, like templates and times, is checked here so
+    //mistakes stop the bot at startup
+    pub fn from_config(config: MonitorConfig) -> Result<MonitorSettings, String> {
+        let templates = config.templates
+            .map(|t| MessageTemplates::new(&t.started, &t.passed, &t.failed, &t.completed, &t.cancelled))
+            .transpose()
+            .map_err(|error| format!("Bad template: {}", error))?;
+        Ok(MonitorSettings {
+            templates,
+            grouping: config.grouping,
+            value_stream_fan_in: config.value_stream_fan_in,
+            quiet_hours: config.quiet_hours.map(QuietHours::from_config).transpose()?,
+            final_stage: config.final_stage,
+            stall_policy: config.stall_policy.map(StallPolicy::from_config).transpose()?,
+            retention: config.retention.map(RetentionPolicy::from_config).transpose()?,
+            per_build_messages: config.per_build_messages,
+            pinned_status: config.pinned_status,
+            threaded_details: config.threaded_details,
+            log_excerpt: config.log_excerpt
+                .map(|log_excerpt| LogExcerptPolicy::new(log_excerpt.max_lines, &log_excerpt.error_patterns))
+                .transpose()?,
+            test_reports: config.test_reports,
+        })
+    }
 }
 
 struct BuildInfoMonitor {
     name: String,
     filter_prefix: String,
     post_channel: String,
+    final_stage: Option<FinalStage>,
+    templates: MessageTemplates,
+    retention: RetentionPolicy,
+    stall_policy: Option<StallPolicy>,
+    per_build_messages: bool,
+    pinned_status: Option<PinnedStatus>,
+    //Rather than rewriting the build's message with each stage, keep it as a short rollup and post each stage's
+    //full text as a reply under it
+    threaded_details: bool,
+    quiet_hours: Option<QuietHours>,
+    log_excerpt: Option<LogExcerptPolicy>,
+    test_reports: Option<TestReportPolicy>,
+    grouping: Grouping,
+    //Pick up pipelines downstream of this monitor's in the value stream map, whatever they're called, and add their
+    //stages to the message of the build that set them off. It costs a value stream map request for every pipeline no
+    //monitor's prefix matches, so it's off unless a monitor's config turns it on.
+    value_stream_fan_in: bool,
+}
+
+//What makes stage notifications part of the same build for a monitor. Modification ids break down when a pipeline
+//is triggered by hand or on a timer without a new commit, so monitors like that can group by something else.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Grouping {
+    ModificationId,
+    Revision,
+    PipelineLabel,
+    ValueStreamRoot,
+}
+
+//Overnight builds post nothing while the window is open, other than failures on the critical stages; the latest
+//message for each build is kept and they all go out in one message once the window closes. Start and end are local
+//times in the timezone, so the window follows daylight saving.
+struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+    timezone: Tz,
+    critical_stages: Vec<String>,
+}
+
+impl QuietHours {
+    fn from_config(config: QuietHoursConfig) -> Result<QuietHours, String> {
+        let parse_time = |time: &str| NaiveTime::parse_from_str(time, "%H:%M")
+            .map_err(|e| format!("Quiet hours time '{}' isn't HH:MM: {}", time, e));
+        Ok(QuietHours {
+            start: parse_time(&config.start)?,
+            end: parse_time(&config.end)?,
+            timezone: config.timezone.parse()
+                .map_err(|e| format!("Quiet hours timezone '{}' isn't an IANA timezone: {}", config.timezone, e))?,
+            critical_stages: config.critical_stages,
+        })
+    }
+
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        let local_time = now.with_timezone(&self.timezone).time();
+        if self.start <= self.end {
+            self.start <= local_time && local_time < self.end
+        } else {
+            local_time >= self.start || local_time < self.end
+        }
+    }
+
+    //Critical stages can be given as a whole pipeline or as pipeline/step
+    fn is_critical(&self, stage_name: &str, build_step: &str) -> bool {
+        let stage_key = format!("{}/{}", stage_name, build_step);
+        self.critical_stages.iter().any(|critical| *critical == stage_name || *critical == stage_key)
+    }
+}
+
+//One pinned message per monitor that's kept up to date with the latest few builds, for channels that would rather
+//not get a new message for every revision. Its timestamp is kept in the store so restarts don't pin another one.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PinnedStatus {
+    latest_builds: usize,
+}
+
+#[derive(Default)]
+struct PinnedStatusMessage {
+    slack_timestamp: Option<String>,
+    builds: VecDeque<(BuildKey, String)>,
 }
 
-#[derive(Hash, PartialEq, Eq)]
+//A build that goes longer than expected_duration without an update and hasn't reached its final stage gets its
+//message marked as stalled
+struct StallPolicy {
+    expected_duration: Duration,
+    thread_alert: bool,
+}
+
+impl StallPolicy {
+    fn from_config(config: StallPolicyConfig) -> Result<StallPolicy, String> {
+        if config.expected_duration_minutes == 0 {
+            return Err("A stall policy's expected_duration_minutes has to be more than 0".to_string());
+        }
+        Ok(StallPolicy {
+            expected_duration: Duration::minutes(i64::from(config.expected_duration_minutes)),
+            thread_alert: config.thread_alert,
+        })
+    }
+}
+
+//How long a build's message keeps getting updated after the last notification for it. Builds kept until their
+//final stage completes are still dropped after MAX_ENTRY_IDLE_DAYS so abandoned ones don't pile up.
+struct RetentionPolicy {
+    idle_time: Duration,
+    keep_until_final_stage: bool,
+}
+
+impl RetentionPolicy {
+    fn from_config(config: RetentionConfig) -> Result<RetentionPolicy, String> {
+        let idle_time = match config.idle_time_minutes {
+            Some(0) => return Err("A retention policy's idle_time_minutes has to be more than 0".to_string()),
+            Some(minutes) => Duration::minutes(i64::from(minutes)),
+            None => RetentionPolicy::default().idle_time,
+        };
+        Ok(RetentionPolicy { idle_time, keep_until_final_stage: config.keep_until_final_stage })
+    }
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> RetentionPolicy {
+        RetentionPolicy {
+            idle_time: Duration::hours(4),
+            keep_until_final_stage: false,
+        }
+    }
+}
+
+const MAX_ENTRY_IDLE_DAYS: i64 = 7;
+const MAX_FAILING_TESTS_LISTED: usize = 25;
+const RECENT_EVENT_LIMIT: usize = 500;
+
+//The stage whose passing means a revision has made it all the way out, used to measure lead time
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FinalStage {
+    pipeline_name: String,
+    build_step: String,
+}
+
+impl BuildInfoMonitor {
+    fn is_final_stage(&self, pipeline_name: &str, build_step: &str) -> bool {
+        match &self.final_stage {
+            Some(final_stage) => final_stage.pipeline_name == pipeline_name && final_stage.build_step == build_step,
+            None => false,
+        }
+    }
+
+    fn apply_settings(&mut self, settings: MonitorSettings) {
+        if let Some(templates) = settings.templates {
+            self.templates = templates;
+        }
+        if let Some(grouping) = settings.grouping {
+            self.grouping = grouping;
+        }
+        if let Some(value_stream_fan_in) = settings.value_stream_fan_in {
+            self.value_stream_fan_in = value_stream_fan_in;
+        }
+        if settings.quiet_hours.is_some() {
+            self.quiet_hours = settings.quiet_hours;
+        }
+        if settings.final_stage.is_some() {
+            self.final_stage = settings.final_stage;
+        }
+        if settings.stall_policy.is_some() {
+            self.stall_policy = settings.stall_policy;
+        }
+        if let Some(retention) = settings.retention {
+            self.retention = retention;
+        }
+        if let Some(per_build_messages) = settings.per_build_messages {
+            self.per_build_messages = per_build_messages;
+        }
+        if settings.pinned_status.is_some() {
+            self.pinned_status = settings.pinned_status;
+        }
+        if let Some(threaded_details) = settings.threaded_details {
+            self.threaded_details = threaded_details;
+        }
+        if settings.log_excerpt.is_some() {
+            self.log_excerpt = settings.log_excerpt;
+        }
+        if settings.test_reports.is_some() {
+            self.test_reports = settings.test_reports;
+        }
+    }
+
+    //Settings that can't work together are refused when the monitor is built, rather than quietly misbehaving later
+    fn validate(&self) -> Result<(), String> {
+        if self.retention.keep_until_final_stage && self.final_stage.is_none() {
+            return Err(format!("Monitor {} keeps builds until their final stage but doesn't have one", self.name));
+        }
+        //Without a final stage a build never counts as completed, so every build would end up marked as stalled
+        if self.stall_policy.is_some() && self.final_stage.is_none() {
+            return Err(format!("Monitor {} watches for stalled builds but doesn't have a final stage", self.name));
+        }
+        match &self.pinned_status {
+            Some(pinned_status) if pinned_status.latest_builds == 0 =>
+                return Err(format!("Monitor {}'s pinned status message has to show at least one build", self.name)),
+            None if !self.per_build_messages =>
+                return Err(format!("Monitor {} has neither per build messages nor a pinned status message", self.name)),
+            _ => (),
+        }
+        match &self.test_reports {
+            Some(test_reports) if test_reports.stages.is_empty() =>
+                Err(format!("Monitor {} looks for test reports but not from any stages", self.name)),
+            _ => Ok(()),
+        }
+    }
+}
+
+struct StalledBuild<'a> {
+    monitor: &'a BuildInfoMonitor,
+    stall_policy: &'a StallPolicy,
+    slack_timestamp: String,
+    last_stage: String,
+    message_text: String,
+}
+
+//Builds that have gone without an update for longer than their monitor expects. Ones that have failed are waiting on
+//someone rather than stuck, so they're left alone along with ones that finished or were already marked.
+fn stalled_builds<'a>(monitors: &'a [BuildInfoMonitor], message_index: &HashMap<BuildInfoIndex, BuildInfoEntry>,
+                      now: DateTime<Utc>) -> Vec<StalledBuild<'a>> {
+    message_index.iter()
+        .filter(|(_, entry)| !entry.failed && !entry.completed && !entry.stalled && !entry.superseded)
+        .filter_map(|(index, entry)| {
+            let monitor = monitors.iter().find(|monitor| monitor.name == index.monitor_name)?;
+            let stall_policy = monitor.stall_policy.as_ref()?;
+            if now.signed_duration_since(entry.last_update_time) < stall_policy.expected_duration {
+                return None;
+            }
+            Some(StalledBuild {
+                monitor,
+                stall_policy,
+                slack_timestamp: entry.slack_timestamp.clone(),
+                last_stage: entry.last_stage.clone(),
+                message_text: entry.message_text.clone(),
+            })
+        })
+        .collect()
+}
+
+//Whether a newer build passing stage_key leaves this one behind for good: it has to be older, not already past that
+//stage, and not finished or marked already
+fn is_superseded_by(build_key: &BuildKey, entry: &BuildInfoEntry, newer_key: &BuildKey, stage_key: &str) -> bool {
+    build_key.is_before(newer_key) && !entry.completed && !entry.superseded
+        && !entry.passed_stages.iter().any(|stage| stage == stage_key)
+}
+
+//The first pipeline upstream of pipeline_name that a fan-in monitor's prefix matches, with that monitor
+const HISTORY_LENGTH: usize = 5;
+
+fn history_text(pipeline_name: &str, history_items: &[HistoryItem]) -> String {
+    if history_items.is_empty() {
+        return format!("GoCD doesn't have any runs of {} with a commit behind them.", pipeline_name);
+    }
+    let lines: Vec<String> = history_items.iter().take(HISTORY_LENGTH)
+        .map(|item| {
+            let revision: String = item.revision.as_ref()
+                .map(|revision| revision.chars().take(8).collect())
+                .unwrap_or_else(|| item.id.to_string());
+            format!("• {} ({}) by {}", item.counter, revision, item.author.as_deref().unwrap_or("unknown"))
+        })
+        .collect();
+    format!("Latest runs of {}:\n{}", pipeline_name, lines.join("\n"))
+}
+
+fn find_value_stream_origin<'a>(monitors: &'a [BuildInfoMonitor], pipeline_name: &str,
+                                upstream_pipelines: Vec<(String, u64)>) -> Option<(&'a BuildInfoMonitor, String, u64)> {
+    upstream_pipelines.into_iter()
+        .filter(|(upstream_name, _)| upstream_name != pipeline_name)
+        .find_map(|(upstream_name, upstream_counter)| monitors.iter()
+            .find(|monitor| monitor.value_stream_fan_in && upstream_name.starts_with(&monitor.filter_prefix))
+            .map(|monitor| (monitor, upstream_name, upstream_counter)))
+}
+
+#[derive(Clone, Hash, PartialEq, Eq)]
 struct BuildInfoIndex {
     monitor_name: String,
-    git_index: u64,
+    key: BuildKey,
+}
+
+//What groups stage notifications into one build, depending on the monitor's Grouping. When GoCD can't be asked it's
+//the pipeline and its counter until the real key can be worked out and the two reconciled.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BuildKey {
+    Modification(u64),
+    Revision(String),
+    Label(String),
+    ValueStream { pipeline_name: String, counter: u64 },
+    Pipeline { pipeline_name: String, counter: u64 },
+}
+
+impl BuildKey {
+    //Only modification ids and counters of the same root pipeline say which build came first, anything else never
+    //counts as older
+    fn is_before(&self, newer: &BuildKey) -> bool {
+        match (self, newer) {
+            (BuildKey::Modification(modification_id), BuildKey::Modification(newer_id)) => modification_id < newer_id,
+            (BuildKey::ValueStream { pipeline_name, counter },
+                BuildKey::ValueStream { pipeline_name: newer_pipeline_name, counter: newer_counter }) =>
+                pipeline_name == newer_pipeline_name && counter < newer_counter,
+            _ => false,
+        }
+    }
+
+    pub fn is_fallback(&self) -> bool {
+        matches!(self, BuildKey::Pipeline { .. })
+    }
 }
 
 impl BuildInfoManager {
-    pub fn new(slack_token: &str, gocd_token: &str) -> BuildInfoManager {
-        BuildInfoManager {
+    pub fn new(slack_token: &str, gocd_credentials: GoCDCredentials, store: BotStore, user_directory: UserDirectory,
+               mut monitor_settings: HashMap<String, MonitorSettings>, config_status: Vec<(&'static str, ConfigLoad)>)
+    -> Result<BuildInfoManager, String> {
+        let mut info_monitors = vec![
+            BuildInfoMonitor {
+                name: "Delorean".to_string(),
+                filter_prefix: "Delorean_".to_string(),
+                post_channel: "CCDJ9UWAZ".to_string(),
+                final_stage: None,
+                templates: MessageTemplates::default_templates(),
+                retention: RetentionPolicy::default(),
+                stall_policy: None,
+                per_build_messages: true,
+                pinned_status: None,
+                threaded_details: false,
+                quiet_hours: None,
+                log_excerpt: None,
+                test_reports: None,
+                grouping: Grouping::ModificationId,
+                value_stream_fan_in: false,
+            }
+        ];
+        for monitor in &mut info_monitors {
+            if let Some(settings) = monitor_settings.remove(&monitor.name) {
+                monitor.apply_settings(settings);
+            }
+            monitor.validate()?;
+        }
+        if let Some(monitor_name) = monitor_settings.keys().next() {
+            return Err(format!("There's config for a monitor called {}, but no such monitor", monitor_name));
+        }
+        let (event_sender, event_receiver) = channel();
+        Ok(BuildInfoManager {
             message_index: Mutex::new(HashMap::new()),
             slack_instance_token: slack_token.to_string(),
             slack_client: default_client().unwrap(),
-            last_cleanout_time: RwLock::new(Utc::now()),
-            info_monitors: vec![
-                BuildInfoMonitor {
-                    name: "Delorean".to_string(),
-                    filter_prefix: "Delorean_".to_string(),
-                    post_channel: "CCDJ9UWAZ".to_string(),
+            info_monitors,
+            gocd_talker: GoCDInfo::create(gocd_credentials),
+            build_history: Mutex::new(BuildHistory::new()),
+            metrics: Metrics::new(),
+            health: HealthTracker::new(),
+            pinned_statuses: Mutex::new(HashMap::new()),
+            store,
+            user_directory,
+            quiet_hour_buffers: Mutex::new(HashMap::new()),
+            authors_notified: Mutex::new(HashMap::new()),
+            event_sender: Mutex::new(event_sender),
+            event_receiver: Mutex::new(Some(event_receiver)),
+            pending_events: AtomicUsize::new(0),
+            recent_event_ids: Mutex::new(VecDeque::new()),
+            report_parser: ReportParser::new(),
+            config_status,
+        })
+    }
+
+    fn call_api<T, E: Debug>(&self, service: &'static str, method: &'static str, call: impl FnOnce() -> Result<T, E>)
+    -> Result<T, E> {
+        let result = self.metrics.time_call(service, method, call);
+        self.health.record(service, &result);
+        result
+    }
+
+    pub fn health_report(&self) -> (HealthStatus, Value) {
+        let slack_auth = self.health.check_slack_auth(|| {
+            self.call_api("slack", "auth.test", || auth::test(&self.slack_client, &self.slack_instance_token))
+                .map(|response| response.user.unwrap_or_default())
+                .map_err(|error| format!("{:?}", error))
+        });
+        let gocd_auth = self.health.check_gocd_auth(|| {
+            match self.call_api("gocd", "current_user", || self.gocd_talker.get_current_user()) {
+                Ok(login_name) => GoCDAuthCheck::Authenticated(login_name),
+                Err(GoCDError::Auth) => GoCDAuthCheck::Rejected(GoCDError::Auth.to_string()),
+                Err(error) => GoCDAuthCheck::Unreachable(error.to_string()),
+            }
+        });
+        let mut config = self.config_status.clone();
+        config.push(("gocd_credentials", self.gocd_talker.credentials_status()));
+        let (status, mut body) = self.health.report(&slack_auth, &gocd_auth, &config);
+        let monitor_names: Vec<&str> = self.info_monitors.iter().map(|monitor| monitor.name.as_str()).collect();
+        body["monitors"] = json!(monitor_names);
+        (status, body)
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let message_index_size = self.message_index.lock().unwrap().len();
+        let build_history_size = self.build_history.lock().unwrap().len();
+        let pending_events = self.pending_events.load(Ordering::SeqCst);
+        let quiet_hour_buffered = self.quiet_hour_buffers.lock().unwrap().values().map(|buffered| buffered.len()).sum();
+        let authors_notified_size = self.authors_notified.lock().unwrap().len();
+        let mut output = self.metrics.render(&[
+            ("build_bot_message_index_size", "Builds with a Slack message being kept up to date", message_index_size),
+            ("build_bot_build_history_size", "Stage results queued up for digests", build_history_size),
+            ("build_bot_pending_stage_events", "Stage events waiting on the event worker", pending_events),
+            ("build_bot_quiet_hour_buffered_builds", "Builds held back until quiet hours end", quiet_hour_buffered),
+            ("build_bot_authors_notified_size", "Builds whose authors have had a failure DM", authors_notified_size),
+        ]);
+        output.push_str(&render_lead_times(&self.lead_time_report()));
+        output
+    }
+
+    pub fn lead_time_report(&self) -> Vec<(String, LeadTimePercentiles)> {
+        let history = self.build_history.lock().unwrap();
+        self.info_monitors.iter()
+            .filter_map(|monitor| history.lead_time_percentiles(&monitor.name).map(|p| (monitor.name.clone(), p)))
+            .collect()
+    }
+
+    pub fn post_digests(&self, period: DigestPeriod) {
+        let digests: Vec<(&BuildInfoMonitor, String)> = {
+            let mut history = self.build_history.lock().unwrap();
+            history.prune(Duration::days(8));
+            self.info_monitors.iter()
+                .map(|monitor| (monitor, history.summarize(&monitor.name, Utc::now() - period.duration())))
+                .filter(|(_, summary)| summary.build_count > 0)
+                .map(|(monitor, summary)| (monitor, summary.to_message(&monitor.name, period.name())))
+                .collect()
+        };
+        for (monitor, message_text) in digests {
+            let request = PostMessageRequest {
+                channel: &monitor.post_channel,
+                text: &message_text,
+                ..Default::default()
+            };
+            info!("About to post {} digest for {}", period.name(), monitor.name);
+            let result = self.call_api("slack", "chat.postMessage",
+                || post_message(&self.slack_client, &self.slack_instance_token, &request));
+            if let Err(error) = result {
+                error!("Got Slack Post error for digest: {:?}", error);
+            }
+        }
+    }
+
+    pub fn flush_quiet_hour_buffers(&self) {
+        let now = Utc::now();
+        for monitor in &self.info_monitors {
+            match &monitor.quiet_hours {
+                Some(quiet_hours) if !quiet_hours.is_active(now) => (),
+                _ => continue,
+            }
+            let buffered = match self.quiet_hour_buffers.lock().unwrap().remove(&monitor.name) {
+                Some(buffered) => buffered,
+                None => continue,
+            };
+            let build_lines: Vec<String> = buffered.values().map(|text| format!("• {}", text)).collect();
+            let message_text = format!("*{} builds during quiet hours*\n{}", monitor.name, build_lines.join("\n"));
+            let request = PostMessageRequest {
+                channel: &monitor.post_channel,
+                text: &message_text,
+                ..Default::default()
+            };
+            info!("Posting {} builds buffered during quiet hours for {}", buffered.len(), monitor.name);
+            if let Err(error) = self.call_api("slack", "chat.postMessage",
+                || post_message(&self.slack_client, &self.slack_instance_token, &request)) {
+                error!("Got Slack Post error for quiet hours digest: {:?}", error);
+            }
+        }
+    }
+
+    pub fn clear_old_message_entries(&self) {
+        let default_retention = RetentionPolicy::default();
+        let mut message_index = self.message_index.lock().unwrap();
+        message_index.retain(|index, entry| {
+            let retention = self.info_monitors.iter()
+                .find(|monitor| monitor.name == index.monitor_name)
+                .map_or(&default_retention, |monitor| &monitor.retention);
+            let idle_time = Utc::now().signed_duration_since(entry.last_update_time);
+            if retention.keep_until_final_stage && !entry.completed && !entry.superseded {
+                idle_time < Duration::days(MAX_ENTRY_IDLE_DAYS)
+            } else {
+                idle_time < retention.idle_time
+            }
+        });
+        self.authors_notified.lock().unwrap()
+            .retain(|_, time| Utc::now().signed_duration_since(*time) < Duration::days(MAX_ENTRY_IDLE_DAYS));
+    }
+
+    pub fn flaky_stage_report(&self, monitor_name: Option<&str>, limit: usize) -> Vec<StageFlakiness> {
+        let mut scores = self.build_history.lock().unwrap().flakiness_scores(monitor_name);
+        scores.truncate(limit);
+        scores
+    }
+
+    fn build_message_text(&self, monitor: &BuildInfoMonitor, key: &BuildKey, history_item: Option<&HistoryItem>,
+                          event: &StageEvent, is_new_build: bool) -> String {
+        let stage_name = event.pipeline_name.as_str();
+        let build_step = event.stage_name.as_str();
+        let (flaky_stages, lead_time, first_record_time) = {
+            let history = self.build_history.lock().unwrap();
+            (history.flaky_stages_for_build(&monitor.name, key),
+                history.lead_time_for_build(&monitor.name, key),
+                history.first_record_time(&monitor.name, key))
+        };
+        let mut notes = String::new();
+        if !flaky_stages.is_empty() {
+            notes.push_str(&format!(" (flaky: {} failed before passing on a rerun)", flaky_stages.join(", ")));
+        }
+        if let Some(lead_time) = lead_time {
+            notes.push_str(&format!(", {} from commit to the final stage", format_duration(lead_time)));
+        }
+
+        let mut values = HashMap::new();
+        values.insert("monitor", monitor.name.clone());
+        values.insert("stage", stage_name.to_string());
+        values.insert("step", event.stage_label());
+        values.insert("result", event.result.name().to_string());
+        values.insert("counter", event.pipeline_counter.to_string());
+        //GoCD's API is preferred, with what came in the notification as a backup
+        let first_change = event.details.changes.first();
+        values.insert("revision", history_item.and_then(|item| item.revision.clone())
+            .or_else(|| first_change.map(|change| change.revision.clone()))
+            .unwrap_or_default());
+        values.insert("author", history_item.and_then(|item| item.author.clone())
+            .or_else(|| first_change.map(|change| change.author.clone()))
+            .unwrap_or_default());
+        values.insert("triggered_by", event.details.triggered_by.clone().unwrap_or_default());
+        values.insert("duration", first_record_time
+            .map(|time| format_duration(Utc::now().signed_duration_since(time)))
+            .unwrap_or_default());
+        values.insert("lead_time", lead_time.map(format_duration).unwrap_or_default());
+        values.insert("link", event.details.gocd_url.clone()
+            .unwrap_or_else(|| gocd::pipeline_url(stage_name, event.pipeline_counter)));
+        values.insert("notes", notes);
+
+        let template = match event.result {
+            StageResult::Passed if monitor.is_final_stage(stage_name, build_step) => &monitor.templates.completed,
+            StageResult::Passed if is_new_build => &monitor.templates.started,
+            StageResult::Passed => &monitor.templates.passed,
+            StageResult::Cancelled => &monitor.templates.cancelled,
+            StageResult::Failed | StageResult::Building | StageResult::Unknown => &monitor.templates.failed,
+        };
+        template.render(&values)
+    }
+
+    fn build_rollup_text(&self, monitor: &BuildInfoMonitor, index: &BuildInfoIndex,
+                         history_item: Option<&HistoryItem>, stage_key: &str, event: &StageEvent) -> String {
+        let mut passed_stages = self.message_index.lock().unwrap().get(index)
+            .map(|entry| entry.passed_stages.clone())
+            .unwrap_or_default();
+        if event.result == StageResult::Passed && !passed_stages.iter().any(|stage| stage == stage_key) {
+            passed_stages.push(stage_key.to_string());
+        }
+        let revision = history_item.and_then(|item| item.revision.clone())
+            .or_else(|| event.details.changes.first().map(|change| change.revision.clone()))
+            .map(|revision| format!(" ({})", revision.chars().take(8).collect::<String>()))
+            .unwrap_or_default();
+        format!("GoCD Build for {}{}: {} stage{} passed, latest {}/{} {}", monitor.name, revision,
+            passed_stages.len(), if passed_stages.len() == 1 { "" } else { "s" }, event.pipeline_name,
+            event.stage_label(), event.result.name())
+    }
+
+    //Builds GoCD couldn't tell us about are recorded under their fallback key, and moved over when it's reconciled
+    fn record_build_result(&self, monitor: &BuildInfoMonitor, key: &BuildKey, history_item: Option<&HistoryItem>,
+                           event: &StageEvent) {
+        let stage_name = &event.pipeline_name;
+        let build_step = &event.stage_name;
+        self.metrics.record_stage_result(stage_name, build_step, event.result.name());
+        //A cancelled stage didn't pass or fail, so it's left out of the history the stats come from
+        if event.result == StageResult::Cancelled {
+            return;
+        }
+        let mut history = self.build_history.lock().unwrap();
+        if let Some(last_record_time) = history.last_record_time(&monitor.name, key) {
+            if let Ok(duration) = Utc::now().signed_duration_since(last_record_time).to_std() {
+                self.metrics.record_stage_duration(stage_name, build_step, duration);
+            }
+        }
+        history.add(BuildRecord {
+            monitor_name: monitor.name.clone(),
+            build_key: key.clone(),
+            pipeline_name: stage_name.to_string(),
+            build_step: build_step.to_string(),
+            failed: event.result == StageResult::Failed,
+            time: event.timestamp,
+            commit_time: history_item.and_then(|item| item.modified_time),
+        });
+        if monitor.is_final_stage(stage_name, build_step) && event.result == StageResult::Passed {
+            history.record_lead_time(&monitor.name, key, history_item.and_then(|item| item.modified_time));
+        }
+    }
+
+    pub fn mark_stalled_builds(&self) {
+        let stalled_builds = stalled_builds(&self.info_monitors, &self.message_index.lock().unwrap(), Utc::now());
+        for StalledBuild { monitor, stall_policy, slack_timestamp, last_stage, message_text } in stalled_builds {
+            let stalled_text = format!("{}\n:warning: Stalled at {}, no progress for over {}",
+                message_text, last_stage, format_duration(stall_policy.expected_duration));
+            let request = UpdateRequest {
+                ts: &slack_timestamp,
+                channel: &monitor.post_channel,
+                text: &stalled_text,
+                as_user: Some(true),
+                ..Default::default()
+            };
+            info!("Marking build for {} as stalled at {}", monitor.name, last_stage);
+            if let Err(error) = self.call_api("slack", "chat.update",
+                || update(&self.slack_client, &self.slack_instance_token, &request)) {
+                error!("Got Slack Update error marking build stalled: {:?}", error);
+                continue;
+            }
+            if stall_policy.thread_alert {
+                let alert_text = format!("This build has stalled at {}", last_stage);
+                let alert_request = PostMessageRequest {
+                    channel: &monitor.post_channel,
+                    text: &alert_text,
+                    thread_ts: Some(&slack_timestamp),
+                    ..Default::default()
+                };
+                if let Err(error) = self.call_api("slack", "chat.postMessage",
+                    || post_message(&self.slack_client, &self.slack_instance_token, &alert_request)) {
+                    error!("Got Slack Post error for stall alert: {:?}", error);
                 }
-            ],
-            gocd_talker: GoCDInfo::create(&gocd_token),
+            }
+            let mut message_index = self.message_index.lock().unwrap();
+            if let Some(entry) = message_index.values_mut().find(|entry| entry.slack_timestamp == slack_timestamp) {
+                entry.stalled = true;
+            }
         }
     }
 
-    fn clear_old_message_entries(&self) {
-        match self.last_cleanout_time.try_read() {
-            Err(_) => return,
-            Ok(time) => {
-                if Utc::now().signed_duration_since(*time) < Duration::days(1) {
-                    return;
+    //Once a newer revision has passed a stage, any older build that hasn't got that far yet is never going to be
+    //the one that ships, so its message is struck through and left alone from then on
+    fn mark_superseded_builds(&self, monitor: &BuildInfoMonitor, key: &BuildKey, stage_key: &str, revision: &str) {
+        let superseded_messages: Vec<(String, String)> = {
+            let mut message_index = self.message_index.lock().unwrap();
+            message_index.iter_mut()
+                .filter(|(index, entry)| index.monitor_name == monitor.name
+                    && is_superseded_by(&index.key, entry, key, stage_key))
+                .map(|(_, entry)| {
+                    entry.superseded = true;
+                    (entry.slack_timestamp.clone(), entry.message_text.clone())
+                })
+                .collect()
+        };
+        for (slack_timestamp, message_text) in superseded_messages {
+            let superseded_text = format!("~{}~\nSuperseded by {}", message_text, revision);
+            let request = UpdateRequest {
+                ts: &slack_timestamp,
+                channel: &monitor.post_channel,
+                text: &superseded_text,
+                as_user: Some(true),
+                ..Default::default()
+            };
+            info!("Marking build for {} as superseded by {}", monitor.name, revision);
+            if let Err(error) = self.call_api("slack", "chat.update",
+                || update(&self.slack_client, &self.slack_instance_token, &request)) {
+                error!("Got Slack Update error marking build superseded: {:?}", error);
+            }
+        }
+    }
+
+    fn update_pinned_status(&self, monitor: &BuildInfoMonitor, pinned_status: &PinnedStatus, key: &BuildKey,
+                            message_text: &str) {
+        let mut pinned_statuses = self.pinned_statuses.lock().unwrap();
+        let status_message = pinned_statuses.entry(monitor.name.clone()).or_insert_with(|| PinnedStatusMessage {
+            slack_timestamp: self.store.pinned_status_message(&monitor.name),
+            builds: VecDeque::new(),
+        });
+        status_message.builds.retain(|(build_key, _)| build_key != key);
+        status_message.builds.push_front((key.clone(), message_text.to_string()));
+        status_message.builds.truncate(pinned_status.latest_builds);
+
+        let build_lines: Vec<String> = status_message.builds.iter().map(|(_, text)| format!("• {}", text)).collect();
+        let status_text = format!("*Latest {} builds*\n{}", monitor.name, build_lines.join("\n"));
+        match &status_message.slack_timestamp {
+            Some(slack_timestamp) => {
+                let request = UpdateRequest {
+                    ts: slack_timestamp,
+                    channel: &monitor.post_channel,
+                    text: &status_text,
+                    as_user: Some(true),
+                    ..Default::default()
+                };
+                if let Err(error) = self.call_api("slack", "chat.update",
+                    || update(&self.slack_client, &self.slack_instance_token, &request)) {
+                    error!("Got Slack Update error for pinned status: {:?}", error);
                 }
+            },
+            None => {
+                let request = PostMessageRequest {
+                    channel: &monitor.post_channel,
+                    text: &status_text,
+                    ..Default::default()
+                };
+                match self.call_api("slack", "chat.postMessage",
+                    || post_message(&self.slack_client, &self.slack_instance_token, &request)) {
+                    Err(error) => error!("Got Slack Post error for pinned status: {:?}", error),
+                    Ok(response) => {
+                        if let Some(timestamp) = response.ts {
+                            let pin_request = pins::AddRequest {
+                                channel: &monitor.post_channel,
+                                timestamp: Some(&timestamp),
+                                ..Default::default()
+                            };
+                            if let Err(error) = self.call_api("slack", "pins.add",
+                                || pins::add(&self.slack_client, &self.slack_instance_token, &pin_request)) {
+                                error!("Got Slack error pinning status message: {:?}", error);
+                            }
+                            self.store.set_pinned_status_message(&monitor.name, &timestamp);
+                            status_message.slack_timestamp = Some(timestamp);
+                        }
+                    },
+                }
+            },
+        }
+    }
+
+    fn lookup_slack_user_by_email(&self, email: &str) -> Result<Option<String>, String> {
+        let response: Value = self.call_api("slack", "users.lookupByEmail", || self.slack_client
+            .get("https://slack.com/api/users.lookupByEmail")
+            .query(&[("token", self.slack_instance_token.as_str()), ("email", email)])
+            .send().map_err(|e| format!("Request Error: {}", e))?
+            .json().map_err(|e| format!("JSON parse error: {}", e)))?;
+        match response.get("ok").and_then(|ok| ok.as_bool()) {
+            Some(true) => Ok(response.pointer("/user/id").and_then(|id| id.as_str()).map(|id| id.to_string())),
+            _ => match response.get("error").and_then(|e| e.as_str()) {
+                Some("users_not_found") => Ok(None),
+                error => Err(format!("Slack error: {:?}", error)),
+            },
+        }
+    }
+
+    fn send_direct_message(&self, user_id: &str, text: &str) {
+        let request = PostMessageRequest {
+            channel: user_id,
+            text,
+            ..Default::default()
+        };
+        if let Err(error) = self.call_api("slack", "chat.postMessage",
+            || post_message(&self.slack_client, &self.slack_instance_token, &request)) {
+            error!("Got Slack Post error sending direct message: {:?}", error);
+        }
+    }
+
+    pub fn handle_command(&self, user_id: &str, command: BuildCommand) -> String {
+        match command {
+            BuildCommand::Subscribe { target, events } => {
+                let known_target = self.info_monitors.iter().any(|monitor| monitor.name.eq_ignore_ascii_case(&target)
+                    || target.to_lowercase().starts_with(&monitor.filter_prefix.to_lowercase()));
+                if !known_target {
+                    let monitor_names: Vec<&str> = self.info_monitors.iter().map(|m| m.name.as_str()).collect();
+                    return format!("I don't watch anything called {}. Try one of {} or a pipeline in them.", target,
+                        monitor_names.join(", "));
+                }
+                self.store.subscribe(user_id, &target, events);
+                format!("You'll get a DM for {} builds of {}.", events.name(), target)
+            },
+            BuildCommand::Unsubscribe { target } => if self.store.unsubscribe(user_id, &target) {
+                format!("You won't get DMs for {} any more.", target)
+            } else {
+                format!("You weren't subscribed to {}.", target)
+            },
+            BuildCommand::List => {
+                let subscriptions = self.store.subscriptions_for_user(user_id);
+                if subscriptions.is_empty() {
+                    "You aren't subscribed to anything.".to_string()
+                } else {
+                    let lines: Vec<String> = subscriptions.iter()
+                        .map(|s| format!("• {} ({})", s.target, s.events.name()))
+                        .collect();
+                    format!("You're subscribed to:\n{}", lines.join("\n"))
+                }
+            },
+            BuildCommand::History { pipeline_name } => {
+                if !self.watches_pipeline(&pipeline_name) {
+                    return format!("I don't watch a pipeline called {}.", pipeline_name);
+                }
+                match self.call_api("gocd", "history", || self.gocd_talker.get_history(&pipeline_name)) {
+                    Ok(history_items) => history_text(&pipeline_name, &history_items),
+                    Err(error) => format!("I couldn't get the history of {} from GoCD: {}", pipeline_name, error),
+                }
+            },
+            BuildCommand::Trigger { pipeline_name } => {
+                if !self.watches_pipeline(&pipeline_name) {
+                    return format!("I don't watch a pipeline called {}.", pipeline_name);
+                }
+                info!("{} is triggering {}", user_id, pipeline_name);
+                self.call_api("gocd", "schedule", || self.gocd_talker.schedule_pipeline(&pipeline_name))
+                    .unwrap_or_else(|error| format!("GoCD wouldn't trigger {}: {}", pipeline_name, error))
+            },
+            BuildCommand::Rerun { pipeline_name, counter, stage_name } => {
+                if !self.watches_pipeline(&pipeline_name) {
+                    return format!("I don't watch a pipeline called {}.", pipeline_name);
+                }
+                info!("{} is rerunning {}/{}/{}", user_id, pipeline_name, counter, stage_name);
+                self.call_api("gocd", "run_stage", || self.gocd_talker.run_stage(&pipeline_name, counter, &stage_name))
+                    .unwrap_or_else(|error| format!("GoCD wouldn't rerun {}/{}/{}: {}", pipeline_name, counter,
+                        stage_name, error))
+            },
+            BuildCommand::Help => COMMAND_HELP.to_string(),
+        }
+    }
+
+    fn watches_pipeline(&self, pipeline_name: &str) -> bool {
+        self.info_monitors.iter().any(|monitor| pipeline_name.starts_with(&monitor.filter_prefix))
+    }
+
+    fn notify_subscribers(&self, monitor: &BuildInfoMonitor, stage_name: &str, result: StageResult, message_text: &str) {
+        for user_id in self.store.subscribers_for(&monitor.name, stage_name, result) {
+            self.send_direct_message(&user_id, message_text);
+        }
+    }
+
+    //Every author in the build hears about it once, however many of its stages fail or get rerun and fail again
+    fn notify_authors_of_failure(&self, index: &BuildInfoIndex, author_emails: &[String], message_text: &str) {
+        if author_emails.is_empty() || !self.first_failure_of_build(index) {
+            return;
+        }
+        for email in author_emails {
+            let user_id = match self.user_directory.slack_user_for_email(email,
+                |email| self.lookup_slack_user_by_email(email)) {
+                Some(user_id) => user_id,
+                None => continue,
+            };
+            if self.store.is_author_dm_opted_out(&user_id) {
+                continue;
             }
+            info!("Letting {} know their build failed", user_id);
+            self.send_direct_message(&user_id, &format!("{}\n_A build with your commit failed. \
+                Reply \"stop\" to stop getting these, or \"start\" to get them again._", message_text));
         }
+    }
 
-        let mut message_index = self.message_index.lock().unwrap();
-        message_index.retain(|_, entry| Utc::now().signed_duration_since(entry.last_update_time) < Duration::hours(4));
-        let mut mutable_cleanout_time = self.last_cleanout_time.write().unwrap();
-        *mutable_cleanout_time = Utc::now();
+    fn first_failure_of_build(&self, index: &BuildInfoIndex) -> bool {
+        match self.authors_notified.lock().unwrap().entry(index.clone()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(Utc::now());
+                true
+            },
+        }
+    }
+
+    //Posts under the build's message, or straight into the channel when the build doesn't have one
+    fn post_to_build_thread(&self, monitor: &BuildInfoMonitor, index: &BuildInfoIndex, text: &str) {
+        let slack_timestamp = self.message_index.lock().unwrap().get(index).map(|entry| entry.slack_timestamp.clone());
+        match slack_timestamp {
+            Some(slack_timestamp) => self.post_thread_reply(&monitor.post_channel, &slack_timestamp, text),
+            None => {
+                let request = PostMessageRequest {
+                    channel: &monitor.post_channel,
+                    text,
+                    ..Default::default()
+                };
+                if let Err(error) = self.call_api("slack", "chat.postMessage",
+                    || post_message(&self.slack_client, &self.slack_instance_token, &request)) {
+                    error!("Got Slack Post error: {:?}", error);
+                }
+            },
+        }
+    }
+
+    //The run of the stage the event is about, for the log excerpts and test reports that hang off its jobs
+    fn fetch_stage_run(&self, event: &StageEvent) -> Option<Stage> {
+        match self.call_api("gocd", "stage", || self.gocd_talker.get_stage(&event.pipeline_name,
+            event.pipeline_counter, &event.stage_name, event.stage_counter)) {
+            Ok(stage_run) => Some(stage_run),
+            Err(error) => {
+                error!("Error getting stage run from GoCD: {}", error);
+                None
+            },
+        }
+    }
+
+    fn post_failure_log_excerpts(&self, monitor: &BuildInfoMonitor, policy: &LogExcerptPolicy, index: &BuildInfoIndex,
+                                 stage_run: &Stage, event: &StageEvent) {
+        let (stage_name, counter, build_step) = (&event.pipeline_name, event.pipeline_counter, &event.stage_name);
+        for job in stage_run.jobs.iter().filter(|job| job.failed()) {
+            let console_log = match self.call_api("gocd", "console_log", || self.gocd_talker
+                .get_console_log(stage_name, counter, build_step, stage_run.counter, &job.name)) {
+                Ok(console_log) => console_log,
+                Err(error) => {
+                    error!("Error getting console log for {} from GoCD: {}", job.name, error);
+                    continue;
+                },
+            };
+            //The job's run time is a nice to have, so the excerpt still goes out without it
+            let run_time = self.call_api("gocd", "job", || self.gocd_talker
+                .get_job(stage_name, counter, build_step, stage_run.counter, &job.name))
+                .ok()
+                .and_then(|job| job.run_time())
+                .map(|run_time| format!(" after {}", format_duration(run_time)))
+                .unwrap_or_default();
+            let reply_text = format!("*{}/{}/{}* failed{}:\n```{}```", stage_name, build_step, job.name, run_time,
+                policy.excerpt(&console_log));
+            self.post_to_build_thread(monitor, index, &reply_text);
+        }
+    }
+
+    //Adds up the JUnit reports from every job in the stage, or None if there weren't any to be found
+    fn fetch_test_summary(&self, policy: &TestReportPolicy, stage_run: &Stage, event: &StageEvent)
+    -> Option<TestSummary> {
+        let (stage_name, counter, build_step) = (&event.pipeline_name, event.pipeline_counter, &event.stage_name);
+        let mut summary = TestSummary::default();
+        for job in &stage_run.jobs {
+            let urls = match self.call_api("gocd", "artifacts", || self.gocd_talker.get_artifact_urls(stage_name,
+                counter, build_step, stage_run.counter, &job.name, &policy.artifact_folder)) {
+                Ok(urls) => urls,
+                Err(error) => {
+                    error!("Error listing artifacts for {} from GoCD: {}", job.name, error);
+                    continue;
+                },
+            };
+            for url in urls.iter().filter(|url| url.ends_with(".xml")) {
+                match self.call_api("gocd", "artifact", || self.gocd_talker.get_artifact(url)) {
+                    Ok(report) => summary.add_report(&self.report_parser, &report),
+                    Err(error) => error!("Error getting test report {} from GoCD: {}", url, error),
+                }
+            }
+        }
+        if summary.total() > 0 { Some(summary) } else { None }
+    }
+
+    fn post_thread_reply(&self, post_channel: &str, slack_timestamp: &str, reply_text: &str) {
+        let request = PostMessageRequest {
+            channel: post_channel,
+            text: reply_text,
+            thread_ts: Some(slack_timestamp),
+            ..Default::default()
+        };
+        if let Err(error) = self.call_api("slack", "chat.postMessage",
+            || post_message(&self.slack_client, &self.slack_instance_token, &request)) {
+            error!("Got Slack Post error for thread reply: {:?}", error);
+        }
+    }
+
+    //Entries posted while GoCD couldn't be reached are keyed by pipeline counter, this moves one over to its real key
+    //once that's known, along with its history. If the build already has a message under the real key, the fallback
+    //one is deleted.
+    fn merge_fallback_entry(&self, monitor: &BuildInfoMonitor, fallback_index: &BuildInfoIndex, index: &BuildInfoIndex,
+                            commit_time: Option<DateTime<Utc>>) {
+        self.build_history.lock().unwrap().rekey(&monitor.name, &fallback_index.key, &index.key, commit_time);
+        let duplicate_timestamp = {
+            let mut message_index = self.message_index.lock().unwrap();
+            let fallback_entry = match message_index.remove(fallback_index) {
+                Some(fallback_entry) => fallback_entry,
+                None => return,
+            };
+            info!("Reconciling {:?} for {} to {:?}", fallback_index.key, monitor.name, index.key);
+            match message_index.entry(index.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(fallback_entry);
+                    None
+                },
+                Entry::Occupied(mut entry) => {
+                    let existing_entry = entry.get_mut();
+                    for stage in fallback_entry.passed_stages {
+                        if !existing_entry.passed_stages.contains(&stage) {
+                            existing_entry.passed_stages.push(stage);
+                        }
+                    }
+                    existing_entry.completed |= fallback_entry.completed;
+                    Some(fallback_entry.slack_timestamp)
+                },
+            }
+        };
+        if let Some(status_message) = self.pinned_statuses.lock().unwrap().get_mut(&monitor.name) {
+            for (key, _) in status_message.builds.iter_mut().filter(|(key, _)| *key == fallback_index.key) {
+                *key = index.key.clone();
+            }
+            let mut seen_keys = HashSet::new();
+            status_message.builds.retain(|(key, _)| seen_keys.insert(key.clone()));
+        }
+        let notified_time = self.authors_notified.lock().unwrap().remove(fallback_index);
+        if let Some(time) = notified_time {
+            self.authors_notified.lock().unwrap().entry(index.clone()).or_insert(time);
+        }
+        if let Some(buffered) = self.quiet_hour_buffers.lock().unwrap().get_mut(&monitor.name) {
+            if let Some(message_text) = buffered.remove(&fallback_index.key) {
+                buffered.entry(index.key.clone()).or_insert(message_text);
+            }
+        }
+        if let Some(slack_timestamp) = duplicate_timestamp {
+            let request = DeleteRequest {
+                ts: &slack_timestamp,
+                channel: &monitor.post_channel,
+                as_user: Some(true),
+            };
+            if let Err(error) = self.call_api("slack", "chat.delete",
+                || delete(&self.slack_client, &self.slack_instance_token, &request)) {
+                error!("Got Slack Delete error merging build messages: {:?}", error);
+            }
+        }
+    }
+
+    //None when the monitor's grouping needs something that neither GoCD nor the notification gave us. Details only
+    //come in for the notification's own pipeline, a downstream one's label and changes aren't the origin's.
+    fn build_key(&self, monitor: &BuildInfoMonitor, pipeline_name: &str, counter: u64,
+                 history_item: Option<&HistoryItem>, details: Option<&StageDetails>) -> Option<BuildKey> {
+        match monitor.grouping {
+            Grouping::ModificationId => history_item.map(|item| BuildKey::Modification(item.id)),
+            Grouping::Revision => history_item.and_then(|item| item.revision.clone())
+                .or_else(|| details?.changes.first().map(|change| change.revision.clone()))
+                .map(BuildKey::Revision),
+            Grouping::PipelineLabel => history_item.and_then(|item| item.label.clone())
+                .or_else(|| details?.label.clone())
+                .map(BuildKey::Label),
+            Grouping::ValueStreamRoot => match self.call_api("gocd", "value_stream_map",
+                || self.gocd_talker.get_value_stream(pipeline_name, counter)) {
+                Ok(pipelines) => pipelines.into_iter().next()
+                    .map(|(pipeline_name, counter)| BuildKey::ValueStream { pipeline_name, counter }),
+                Err(error) => {
+                    error!("Error getting value stream map from GoCD: {}", error);
+                    None
+                },
+            },
+        }
+    }
+
+    //For a pipeline no monitor's prefix matches, the monitor and upstream pipeline instance it should be grouped
+    //with, if any fan-in monitor's pipeline set it off
+    fn value_stream_origin(&self, pipeline_name: &str, counter: u64) -> Option<(&BuildInfoMonitor, String, u64)> {
+        if !self.info_monitors.iter().any(|im| im.value_stream_fan_in) {
+            return None;
+        }
+        match self.call_api("gocd", "value_stream_map", || self.gocd_talker.get_value_stream(pipeline_name, counter)) {
+            Ok(upstream_pipelines) => find_value_stream_origin(&self.info_monitors, pipeline_name, upstream_pipelines),
+            Err(error) => {
+                error!("Error getting value stream map from GoCD: {}", error);
+                None
+            },
+        }
+    }
+
+    //What GoCD knows about the commit behind one run of a pipeline
+    fn fetch_history_item(&self, pipeline_name: &str, counter: u64) -> Option<HistoryItem> {
+        match self.call_api("gocd", "pipeline_instance",
+            || self.gocd_talker.get_pipeline_instance(pipeline_name, counter)) {
+            Ok(instance) => match HistoryItem::from_instance(&instance) {
+                //Grouping by the wrong run would merge two builds' messages, so a mismatch counts as not knowing
+                Some(history_item) if history_item.counter != counter => {
+                    error!("Asked GoCD for {}/{} but got run {}", pipeline_name, counter, history_item.counter);
+                    None
+                },
+                Some(history_item) => Some(history_item),
+                None => {
+                    error!("GoCD has no modifications for {}/{}", pipeline_name, counter);
+                    None
+                },
+            },
+            Err(error) => {
+                error!("Error getting {}/{} from GoCD: {}", pipeline_name, counter, error);
+                None
+            },
+        }
+    }
+
+    //Retries GoCD for any builds that still have a message or history keyed by pipeline counter
+    pub fn reconcile_fallback_builds(&self) {
+        let mut fallback_indexes: HashSet<BuildInfoIndex> = self.message_index.lock().unwrap().keys()
+            .filter(|index| index.key.is_fallback())
+            .cloned()
+            .collect();
+        fallback_indexes.extend(self.build_history.lock().unwrap().fallback_keys().into_iter()
+            .map(|(monitor_name, key)| BuildInfoIndex { monitor_name, key }));
+        for fallback_index in fallback_indexes {
+            let monitor = match self.info_monitors.iter().find(|monitor| monitor.name == fallback_index.monitor_name) {
+                Some(monitor) => monitor,
+                None => continue,
+            };
+            if let BuildKey::Pipeline { pipeline_name, counter } = &fallback_index.key {
+                let history_item = match self.fetch_history_item(pipeline_name, *counter) {
+                    Some(history_item) => history_item,
+                    None => continue,
+                };
+                if let Some(key) = self.build_key(monitor, pipeline_name, *counter, Some(&history_item), None) {
+                    let index = BuildInfoIndex { monitor_name: monitor.name.clone(), key };
+                    self.merge_fallback_entry(monitor, &fallback_index, &index, history_item.modified_time);
+                }
+            }
+        }
+    }
+
+    //Hands over the queue of stage events, which only the event worker should be reading
+    pub fn take_event_receiver(&self) -> Option<Receiver<StageEvent>> {
+        self.event_receiver.lock().unwrap().take()
+    }
+
+    //Slack redelivers an event it thinks we didn't acknowledge in time, so true only the first time an id comes in
+    pub fn first_delivery(&self, event_id: &str) -> bool {
+        let mut recent_event_ids = self.recent_event_ids.lock().unwrap();
+        if recent_event_ids.iter().any(|id| id == event_id) {
+            return false;
+        }
+        if recent_event_ids.len() >= RECENT_EVENT_LIMIT {
+            recent_event_ids.pop_front();
+        }
+        recent_event_ids.push_back(event_id.to_string());
+        true
+    }
+
+    //What the event worker calls for each event it takes off the queue
+    pub fn handle_queued_event(&self, event: &StageEvent) {
+        self.handle_stage_event(event);
+        self.pending_events.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn handle_stage_event(&self, event: &StageEvent) {
+        let stage_name = event.pipeline_name.as_str();
+        let build_num = event.pipeline_counter;
+        let origin = match self.info_monitors.iter().find(|im| stage_name.starts_with(&im.filter_prefix)) {
+            Some(monitor) => Some((monitor, stage_name.to_string(), build_num)),
+            None => self.value_stream_origin(stage_name, build_num),
+        };
+        //Downstream pipelines picked up through the value stream map are grouped by the pipeline that set them off
+        if let Some((monitor, origin_name, origin_counter)) = origin {
+            let history_item = self.fetch_history_item(&origin_name, origin_counter);
+            if history_item.is_none() {
+                info!("Grouping {}/{} by pipeline counter until GoCD can tell us more", origin_name, origin_counter);
+            }
+            let fallback_index = BuildInfoIndex {
+                monitor_name: monitor.name.clone(),
+                key: BuildKey::Pipeline { pipeline_name: origin_name.clone(), counter: origin_counter },
+            };
+            let details = if origin_name == stage_name { Some(&event.details) } else { None };
+            let index = match self.build_key(monitor, &origin_name, origin_counter, history_item.as_ref(), details) {
+                Some(key) => {
+                    let index = BuildInfoIndex { monitor_name: monitor.name.clone(), key };
+                    let commit_time = history_item.as_ref().and_then(|item| item.modified_time);
+                    self.merge_fallback_entry(monitor, &fallback_index, &index, commit_time);
+                    index
+                },
+                None => fallback_index,
+            };
+            self.process_stage_event(monitor, event, history_item.as_ref(), index);
+        }
+    }
+
+    fn process_stage_event(&self, monitor: &BuildInfoMonitor, event: &StageEvent, history_item: Option<&HistoryItem>,
+                           index: BuildInfoIndex) {
+        let stage_name = event.pipeline_name.as_str();
+        let build_step = event.stage_name.as_str();
+        let failed = event.result == StageResult::Failed;
+        self.record_build_result(monitor, &index.key, history_item, event);
+        let is_new_build = !self.message_index.lock().unwrap().contains_key(&index);
+        info!("Handling build message for {}", &stage_name);
+        let wants_test_reports = monitor.test_reports.iter().any(|policy| policy.covers(stage_name, build_step));
+        let wants_log_excerpts = failed && monitor.log_excerpt.is_some();
+        let stage_run = if wants_test_reports || wants_log_excerpts {
+            self.fetch_stage_run(event)
+        } else {
+            None
+        };
+        let test_summary = match (&monitor.test_reports, &stage_run) {
+            (Some(policy), Some(stage_run)) if wants_test_reports =>
+                self.fetch_test_summary(policy, stage_run, event),
+            _ => None,
+        };
+        let mut message_text = self.build_message_text(monitor, &index.key, history_item, event, is_new_build);
+        if let Some(test_summary) = &test_summary {
+            message_text.push_str(&format!("\n{}", test_summary.summary_line()));
+        }
+        let held_for_quiet_hours = match &monitor.quiet_hours {
+            Some(quiet_hours) => quiet_hours.is_active(Utc::now())
+                && !(failed && quiet_hours.is_critical(stage_name, build_step)),
+            None => false,
+        };
+        if held_for_quiet_hours {
+            info!("Holding message for {} until quiet hours end", monitor.name);
+            self.quiet_hour_buffers.lock().unwrap().entry(monitor.name.clone()).or_default()
+                .insert(index.key.clone(), message_text.clone());
+        }
+        match &monitor.pinned_status {
+            Some(pinned_status) if !held_for_quiet_hours =>
+                self.update_pinned_status(monitor, pinned_status, &index.key, &message_text),
+            _ => (),
+        }
+        self.notify_subscribers(monitor, stage_name, event.result, &message_text);
+        if failed {
+            let mut author_emails = history_item.map(|item| item.author_emails.clone()).unwrap_or_default();
+            if author_emails.is_empty() {
+                author_emails = event.details.changes.iter()
+                    .filter_map(|change| gocd::email_from_user_name(&change.author))
+                    .collect();
+                author_emails.sort();
+                author_emails.dedup();
+            }
+            self.notify_authors_of_failure(&index, &author_emails, &message_text);
+        }
+        let stage_key = format!("{}/{}", stage_name, build_step);
+        if monitor.per_build_messages && !held_for_quiet_hours {
+            let (message_text, thread_reply) = if monitor.threaded_details {
+                (self.build_rollup_text(monitor, &index, history_item, &stage_key, event), Some(message_text))
+            } else {
+                (message_text, None)
+            };
+            let build_update = BuildUpdate {
+                message_text,
+                thread_reply,
+                stage_key: stage_key.clone(),
+                stage_label: format!("{}/{}", stage_name, event.stage_label()),
+                result: event.result,
+                completed: event.result == StageResult::Passed && monitor.is_final_stage(stage_name, build_step),
+            };
+            self.process_build_message(index.clone(), &monitor.post_channel, build_update);
+        }
+        match (&monitor.log_excerpt, &stage_run) {
+            (Some(policy), Some(stage_run)) if failed && !held_for_quiet_hours =>
+                self.post_failure_log_excerpts(monitor, policy, &index, stage_run, event),
+            _ => (),
+        }
+        match &test_summary {
+            Some(test_summary) if !test_summary.failing_tests.is_empty() && !held_for_quiet_hours =>
+                self.post_to_build_thread(monitor, &index, &test_summary.failing_tests_text(MAX_FAILING_TESTS_LISTED)),
+            _ => (),
+        }
+        match history_item {
+            Some(history_item) if event.result == StageResult::Passed => {
+                let revision = history_item.revision.as_ref()
+                    .map(|revision| revision.chars().take(8).collect())
+                    .unwrap_or_else(|| history_item.id.to_string());
+                self.mark_superseded_builds(monitor, &index.key, &stage_key, &revision);
+            },
+            _ => (),
+        }
     }
 
-    fn process_build_message(&self, index: BuildInfoIndex, message_text: &str, post_channel: &str, failed: bool) {
-        match self.message_index.lock().unwrap().entry(index) {
+    fn process_build_message(&self, index: BuildInfoIndex, post_channel: &str, build_update: BuildUpdate) {
+        let BuildUpdate { message_text, thread_reply, stage_key, stage_label, result, completed } = build_update;
+        let failed = result == StageResult::Failed;
+        let message_text = message_text.as_str();
+        let mut message_index = self.message_index.lock().unwrap();
+        match message_index.entry(index) {
             Entry::Vacant(entry) => {
                 let request = PostMessageRequest {
                     channel: &post_channel,
@@ -83,13 +1372,23 @@ impl BuildInfoManager {
                     ..Default::default()
                 };
                 info!("About to try to create new message with text: '{}'", &request.text);
-                match post_message(&self.slack_client, &self.slack_instance_token, &request) {
+                match self.call_api("slack", "chat.postMessage",
+                    || post_message(&self.slack_client, &self.slack_instance_token, &request)) {
                     Ok(response) => {
                         if let Some(timestamp) = response.ts {
+                            if let Some(reply_text) = &thread_reply {
+                                self.post_thread_reply(post_channel, &timestamp, reply_text);
+                            }
                             entry.insert(BuildInfoEntry {
                                 failed,
+                                completed,
+                                stalled: false,
+                                superseded: false,
+                                passed_stages: if result == StageResult::Passed { vec![stage_key] } else { vec![] },
                                 slack_timestamp: timestamp,
-                                last_update_time: Utc::now()
+                                last_update_time: Utc::now(),
+                                last_stage: stage_label,
+                                message_text: message_text.to_string(),
                             });
                         }
                     },
@@ -98,6 +1397,10 @@ impl BuildInfoManager {
             },
             Entry::Occupied(mut entry) => {
                 let mut info_entry = entry.get_mut();
+                if info_entry.superseded {
+                    info!("Not updating message for superseded build with text: '{}'", message_text);
+                    return;
+                }
                 let request = UpdateRequest {
                     ts: &info_entry.slack_timestamp,
                     channel: &post_channel,
@@ -106,11 +1409,22 @@ impl BuildInfoManager {
                     ..Default::default()
                 };
                 info!("About to try to update moessage with text: '{}'", &request.text);
-                match update(&self.slack_client, &self.slack_instance_token, &request) {
+                match self.call_api("slack", "chat.update",
+                    || update(&self.slack_client, &self.slack_instance_token, &request)) {
                     Err(error) => error!("Got Slack Update error: {:?}", error),
                     Ok(_) => {
+                        if let Some(reply_text) = &thread_reply {
+                            self.post_thread_reply(post_channel, &info_entry.slack_timestamp, reply_text);
+                        }
                         info_entry.last_update_time = Utc::now();
                         info_entry.failed = failed;
+                        info_entry.completed |= completed;
+                        info_entry.stalled = false;
+                        if result == StageResult::Passed && !info_entry.passed_stages.contains(&stage_key) {
+                            info_entry.passed_stages.push(stage_key);
+                        }
+                        info_entry.last_stage = stage_label;
+                        info_entry.message_text = message_text.to_string();
                     }
                 }
             }
@@ -122,58 +1436,543 @@ impl BuildInfoManager {
 mod manager_tests {
     use super::*;
 
+    fn test_manager_with(monitor_settings: HashMap<String, MonitorSettings>) -> Result<BuildInfoManager, String> {
+        BuildInfoManager::new("test_token", GoCDCredentials::Encoded("test_gocd".to_string()), BotStore::in_memory(),
+            UserDirectory::new(HashMap::new()), monitor_settings, vec![])
+    }
+
+    fn test_manager() -> BuildInfoManager {
+        test_manager_with(HashMap::new()).unwrap()
+    }
+
+    fn test_settings(config: &str) -> Result<MonitorSettings, String> {
+        MonitorSettings::from_config(serde_json::from_str(config).unwrap())
+    }
+
+    fn test_monitor(name: &str) -> BuildInfoMonitor {
+        BuildInfoMonitor {
+            name: name.to_string(),
+            filter_prefix: format!("{}_", name),
+            post_channel: "test".to_string(),
+            final_stage: None,
+            templates: MessageTemplates::default_templates(),
+            retention: RetentionPolicy::default(),
+            stall_policy: None,
+            per_build_messages: true,
+            pinned_status: None,
+            threaded_details: false,
+            quiet_hours: None,
+            log_excerpt: None,
+            test_reports: None,
+            grouping: Grouping::ModificationId,
+            value_stream_fan_in: false,
+        }
+    }
+
+    #[test]
+    fn test_monitor_settings() {
+        let mut monitor_settings = HashMap::new();
+        monitor_settings.insert("Delorean".to_string(), test_settings(r#"{
+            "templates": {"failed": "{{monitor}} failed"},
+            "grouping": "revision",
+            "value_stream_fan_in": true,
+            "threaded_details": true,
+            "log_excerpt": {"max_lines": 20, "error_patterns": ["^error", "FAILED"]},
+            "test_reports": {"stages": ["Delorean_Build/Test"], "artifact_folder": "test-reports"},
+            "quiet_hours": {"start": "22:00", "end": "07:00", "timezone": "Europe/London",
+                "critical_stages": ["Delorean_Deploy"]}
+        }"#).unwrap());
+        let manager = test_manager_with(monitor_settings).unwrap();
+        let monitor = &manager.info_monitors[0];
+        assert!(monitor.threaded_details);
+        let log_excerpt = monitor.log_excerpt.as_ref().unwrap();
+        assert_eq!((log_excerpt.max_lines, log_excerpt.error_patterns.len()), (20, 2));
+        let test_reports = monitor.test_reports.as_ref().unwrap();
+        assert!(test_reports.covers("Delorean_Build", "Test") && !test_reports.covers("Delorean_Build", "Compile"));
+        assert_eq!(test_reports.artifact_folder, "test-reports");
+        assert_eq!(monitor.grouping, Grouping::Revision);
+        assert!(monitor.value_stream_fan_in);
+        let quiet_hours = monitor.quiet_hours.as_ref().unwrap();
+        assert_eq!((quiet_hours.start, quiet_hours.timezone), (NaiveTime::from_hms(22, 0, 0), chrono_tz::Europe::London));
+        assert!(quiet_hours.is_critical("Delorean_Deploy", "Production"));
+        let mut values = HashMap::new();
+        values.insert("monitor", "Delorean".to_string());
+        assert_eq!(manager.info_monitors[0].templates.failed.render(&values), "Delorean failed");
+
+        let mut monitor_settings = HashMap::new();
+        monitor_settings.insert("Deloreen".to_string(), MonitorSettings::default());
+        assert_eq!(test_manager_with(monitor_settings).err(),
+            Some("There's config for a monitor called Deloreen, but no such monitor".to_string()));
+
+        let mut monitor_settings = HashMap::new();
+        monitor_settings.insert("Delorean".to_string(), test_settings(r#"{
+            "final_stage": {"pipeline_name": "Delorean_Deploy", "build_step": "Production"},
+            "stall_policy": {"expected_duration_minutes": 90, "thread_alert": true}
+        }"#).unwrap());
+        let manager = test_manager_with(monitor_settings).unwrap();
+        let stall_policy = manager.info_monitors[0].stall_policy.as_ref().unwrap();
+        assert_eq!((stall_policy.expected_duration, stall_policy.thread_alert), (Duration::minutes(90), true));
+        let mut monitor_settings = HashMap::new();
+        monitor_settings.insert("Delorean".to_string(),
+            test_settings(r#"{"stall_policy": {"expected_duration_minutes": 90}}"#).unwrap());
+        assert_eq!(test_manager_with(monitor_settings).err(),
+            Some("Monitor Delorean watches for stalled builds but doesn't have a final stage".to_string()));
+
+        let mut monitor_settings = HashMap::new();
+        monitor_settings.insert("Delorean".to_string(), test_settings(r#"{
+            "final_stage": {"pipeline_name": "Delorean_Deploy", "build_step": "Production"},
+            "retention": {"keep_until_final_stage": true}
+        }"#).unwrap());
+        let manager = test_manager_with(monitor_settings).unwrap();
+        let retention = &manager.info_monitors[0].retention;
+        assert_eq!((retention.idle_time, retention.keep_until_final_stage), (Duration::hours(4), true));
+        let retention = test_settings(r#"{"retention": {"idle_time_minutes": 600}}"#).unwrap().retention.unwrap();
+        assert_eq!((retention.idle_time, retention.keep_until_final_stage), (Duration::hours(10), false));
+        let mut monitor_settings = HashMap::new();
+        monitor_settings.insert("Delorean".to_string(),
+            test_settings(r#"{"retention": {"keep_until_final_stage": true}}"#).unwrap());
+        assert_eq!(test_manager_with(monitor_settings).err(),
+            Some("Monitor Delorean keeps builds until their final stage but doesn't have one".to_string()));
+
+        let mut monitor_settings = HashMap::new();
+        monitor_settings.insert("Delorean".to_string(),
+            test_settings(r#"{"per_build_messages": false, "pinned_status": {"latest_builds": 5}}"#).unwrap());
+        let manager = test_manager_with(monitor_settings).unwrap();
+        assert!(!manager.info_monitors[0].per_build_messages);
+        assert!(!manager.info_monitors[0].threaded_details);
+        assert_eq!(manager.info_monitors[0].pinned_status.as_ref().map(|p| p.latest_builds), Some(5));
+        let mut monitor_settings = HashMap::new();
+        monitor_settings.insert("Delorean".to_string(), test_settings(r#"{"per_build_messages": false}"#).unwrap());
+        assert_eq!(test_manager_with(monitor_settings).err(),
+            Some("Monitor Delorean has neither per build messages nor a pinned status message".to_string()));
+
+        assert!(test_settings(r#"{"log_excerpt": {"max_lines": 20, "error_patterns": ["["]}}"#).err().unwrap()
+            .starts_with("Bad log excerpt error pattern '['"));
+        let mut monitor_settings = HashMap::new();
+        monitor_settings.insert("Delorean".to_string(),
+            test_settings(r#"{"test_reports": {"stages": [], "artifact_folder": "test-reports"}}"#).unwrap());
+        assert_eq!(test_manager_with(monitor_settings).err(),
+            Some("Monitor Delorean looks for test reports but not from any stages".to_string()));
+
+        assert_eq!(test_settings(r#"{"stall_policy": {"expected_duration_minutes": 0}}"#).err(),
+            Some("A stall policy's expected_duration_minutes has to be more than 0".to_string()));
+        assert_eq!(test_settings(r#"{"templates": {"failed": "{{monitr}}"}}"#).err()
+            .map(|error| error.starts_with("Bad template: Invalid 'failed' template")), Some(true));
+        assert_eq!(test_settings(r#"{"quiet_hours": {"start": "10pm", "end": "07:00", "timezone": "UTC"}}"#).err()
+            .map(|error| error.starts_with("Quiet hours time '10pm' isn't HH:MM")), Some(true));
+        assert_eq!(test_settings(r#"{"quiet_hours": {"start": "22:00", "end": "07:00", "timezone": "Eastern"}}"#)
+            .err().map(|error| error.starts_with("Quiet hours timezone 'Eastern' isn't an IANA timezone")), Some(true));
+    }
+
+    #[test]
+    fn test_validate_monitor() {
+        assert!(test_monitor("test").validate().is_ok());
+        let keep_until_final_stage = RetentionPolicy { idle_time: Duration::hours(1), keep_until_final_stage: true };
+        let without_final_stage = BuildInfoMonitor { retention: keep_until_final_stage, ..test_monitor("patient") };
+        assert_eq!(without_final_stage.validate(),
+            Err("Monitor patient keeps builds until their final stage but doesn't have one".to_string()));
+    }
+
+    fn test_entry(slack_timestamp: &str, idle_time: Duration) -> BuildInfoEntry {
+        BuildInfoEntry {
+            failed: false, completed: false, stalled: false, superseded: false, passed_stages: vec![],
+            slack_timestamp: slack_timestamp.to_string(), last_update_time: Utc::now() - idle_time,
+            last_stage: "Delorean_Build/Test".to_string(), message_text: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_stalled_builds() {
+        let stall_policy = StallPolicy { expected_duration: Duration::hours(1), thread_alert: false };
+        let watched_monitor = BuildInfoMonitor {
+            final_stage: Some(FinalStage {
+                pipeline_name: "watched_Deploy".to_string(),
+                build_step: "Production".to_string(),
+            }),
+            stall_policy: Some(stall_policy),
+            ..test_monitor("watched")
+        };
+        assert!(watched_monitor.validate().is_ok());
+        let without_final_stage = BuildInfoMonitor {
+            stall_policy: Some(StallPolicy { expected_duration: Duration::hours(1), thread_alert: false }),
+            ..test_monitor("watched")
+        };
+        assert!(without_final_stage.validate().is_err());
+        let monitors = vec![watched_monitor, test_monitor("unwatched")];
+        let mut message_index = HashMap::new();
+        let mut insert_entry = |monitor_name: &str, git_index: u64, entry: BuildInfoEntry| {
+            let index = BuildInfoIndex { monitor_name: monitor_name.to_string(), key: BuildKey::Modification(git_index) };
+            message_index.insert(index, entry);
+        };
+        insert_entry("watched", 1, test_entry("recent", Duration::minutes(30)));
+        insert_entry("watched", 2, test_entry("stalled", Duration::hours(2)));
+        insert_entry("watched", 3, BuildInfoEntry { failed: true, ..test_entry("failed", Duration::hours(2)) });
+        insert_entry("watched", 4, BuildInfoEntry { completed: true, ..test_entry("completed", Duration::hours(2)) });
+        insert_entry("watched", 5, BuildInfoEntry { stalled: true, ..test_entry("marked", Duration::hours(2)) });
+        insert_entry("watched", 6, BuildInfoEntry { superseded: true, ..test_entry("superseded", Duration::hours(2)) });
+        insert_entry("unwatched", 7, test_entry("unwatched", Duration::hours(2)));
+
+        let stalled = stalled_builds(&monitors, &message_index, Utc::now());
+        let stalled_timestamps: Vec<&str> = stalled.iter().map(|build| build.slack_timestamp.as_str()).collect();
+        assert_eq!(stalled_timestamps, vec!["stalled"]);
+        assert_eq!(stalled[0].monitor.name, "watched");
+    }
+
+    #[test]
+    fn test_is_superseded_by() {
+        let newer_key = BuildKey::Modification(13);
+        let stage_key = "Delorean_Deploy/Staging";
+        let behind = test_entry("behind", Duration::minutes(5));
+        assert!(is_superseded_by(&BuildKey::Modification(12), &behind, &newer_key, stage_key));
+        assert!(!is_superseded_by(&BuildKey::Modification(14), &behind, &newer_key, stage_key));
+        let already_passed = BuildInfoEntry {
+            passed_stages: vec![stage_key.to_string()],
+            ..test_entry("passed", Duration::minutes(5))
+        };
+        assert!(!is_superseded_by(&BuildKey::Modification(12), &already_passed, &newer_key, stage_key));
+        let completed = BuildInfoEntry { completed: true, ..test_entry("completed", Duration::minutes(5)) };
+        assert!(!is_superseded_by(&BuildKey::Modification(12), &completed, &newer_key, stage_key));
+    }
+
+    #[test]
+    fn test_lead_time_at_final_stage() {
+        let mut monitor_settings = HashMap::new();
+        monitor_settings.insert("Delorean".to_string(), test_settings(r#"{
+            "final_stage": {"pipeline_name": "Delorean_Deploy", "build_step": "Production"}
+        }"#).unwrap());
+        let manager = test_manager_with(monitor_settings).unwrap();
+        let monitor = &manager.info_monitors[0];
+        let key = BuildKey::Modification(1955);
+        let history_item = HistoryItem {
+            counter: 1432, id: 1955, modified_time: Some(Utc::now() - Duration::hours(3)), revision: None,
+            author: None, author_emails: vec![], label: None,
+        };
+        let event = |pipeline_name: &str, stage_name: &str| StageEvent {
+            pipeline_name: pipeline_name.to_string(),
+            pipeline_counter: 1432,
+            stage_name: stage_name.to_string(),
+            stage_counter: 1,
+            result: StageResult::Passed,
+            timestamp: Utc::now(),
+            details: Default::default(),
+        };
+        manager.record_build_result(monitor, &key, Some(&history_item), &event("Delorean_Build", "Compile"));
+        manager.record_build_result(monitor, &key, Some(&history_item), &event("Delorean_Deploy", "Staging"));
+        assert!(manager.lead_time_report().is_empty());
+
+        let final_event = event("Delorean_Deploy", "Production");
+        manager.record_build_result(monitor, &key, Some(&history_item), &final_event);
+        let report = manager.lead_time_report();
+        assert_eq!(report.len(), 1);
+        assert_eq!((report[0].0.as_str(), report[0].1.count), ("Delorean", 1));
+        let lead_time = report[0].1.p50;
+        assert!(lead_time >= Duration::hours(3) && lead_time < Duration::hours(3) + Duration::minutes(1));
+        let message_text = manager.build_message_text(monitor, &key, Some(&history_item), &final_event, false);
+        assert!(message_text.contains("3h 0m from commit to the final stage"), "Got '{}'", message_text);
+    }
+
+    #[test]
+    fn test_history_text() {
+        let history_item = |counter, revision: Option<&str>, author: Option<&str>| HistoryItem {
+            counter, id: 1900 + counter, modified_time: None, revision: revision.map(|r| r.to_string()),
+            author: author.map(|a| a.to_string()), author_emails: vec![], label: None,
+        };
+        let history_items: Vec<HistoryItem> = (0..7).rev()
+            .map(|counter| history_item(counter, Some("8d1e2c4a9b7f6e5d"), Some("Marty McFly")))
+            .chain(vec![history_item(7, None, None)])
+            .collect();
+        assert_eq!(history_text("Delorean_Build", &history_items[..2]),
+            "Latest runs of Delorean_Build:\n• 6 (8d1e2c4a) by Marty McFly\n• 5 (8d1e2c4a) by Marty McFly");
+        assert_eq!(history_text("Delorean_Build", &history_items).lines().count(), HISTORY_LENGTH + 1);
+        assert_eq!(history_text("Delorean_Build", &history_items[7..]),
+            "Latest runs of Delorean_Build:\n• 7 (1907) by unknown");
+        assert_eq!(history_text("Delorean_Build", &[]),
+            "GoCD doesn't have any runs of Delorean_Build with a commit behind them.");
+    }
+
+    #[test]
+    fn test_build_rollup_text() {
+        let manager = test_manager();
+        let monitor = &manager.info_monitors[0];
+        let index = BuildInfoIndex { monitor_name: monitor.name.clone(), key: BuildKey::Modification(1955) };
+        let history_item = HistoryItem {
+            counter: 1432, id: 1955, modified_time: None, revision: Some("8d1e2c4a0b1f".to_string()), author: None,
+            author_emails: vec![], label: None,
+        };
+        let event = |stage_name: &str, result| StageEvent {
+            pipeline_name: "Delorean_Build".to_string(),
+            pipeline_counter: 1432,
+            stage_name: stage_name.to_string(),
+            stage_counter: 1,
+            result,
+            timestamp: Utc::now(),
+            details: Default::default(),
+        };
+        assert_eq!(manager.build_rollup_text(monitor, &index, Some(&history_item), "Delorean_Build/Compile",
+            &event("Compile", StageResult::Passed)),
+            "GoCD Build for Delorean (8d1e2c4a): 1 stage passed, latest Delorean_Build/Compile passed");
+
+        manager.message_index.lock().unwrap().insert(index.clone(), BuildInfoEntry {
+            passed_stages: vec!["Delorean_Build/Compile".to_string()],
+            ..test_entry("1355517523.000005", Duration::minutes(5))
+        });
+        let cases = vec![
+            ("Test", StageResult::Passed, "2 stages passed, latest Delorean_Build/Test passed"),
+            ("Compile", StageResult::Passed, "1 stage passed, latest Delorean_Build/Compile passed"),
+            ("Test", StageResult::Failed, "1 stage passed, latest Delorean_Build/Test failed"),
+        ];
+        for (stage_name, result, expected) in cases {
+            let stage_key = format!("Delorean_Build/{}", stage_name);
+            assert_eq!(manager.build_rollup_text(monitor, &index, None, &stage_key, &event(stage_name, result)),
+                format!("GoCD Build for Delorean: {}", expected));
+        }
+    }
+
     #[test]
     fn test_clear_old_message_entries() {
-        let manager = BuildInfoManager::new("test_token", "test_gocd");
+        let mut manager = test_manager();
+        let patient_monitor = BuildInfoMonitor {
+            final_stage: Some(FinalStage {
+                pipeline_name: "Patient_Deploy".to_string(),
+                build_step: "Production".to_string(),
+            }),
+            retention: RetentionPolicy { idle_time: Duration::hours(1), keep_until_final_stage: true },
+            ..test_monitor("patient")
+        };
+        patient_monitor.validate().unwrap();
+        manager.info_monitors.push(patient_monitor);
         {
-            let mut cleanout_time = manager.last_cleanout_time.write().unwrap();
-            *cleanout_time = Utc::now() - Duration::days(2);
             let mut index_map = manager.message_index.lock().unwrap();
-            index_map.insert(
-                BuildInfoIndex { monitor_name: "test".to_string(), git_index: 1 },
-                BuildInfoEntry {
-                    failed: false, slack_timestamp: "test".to_string(), last_update_time: Utc::now() - Duration::hours(1)
-                }
-            );
-            index_map.insert(
-                BuildInfoIndex { monitor_name: "test".to_string(), git_index: 2 },
-                BuildInfoEntry {
-                    failed: false, slack_timestamp: "test".to_string(), last_update_time: Utc::now() - Duration::days(1)
-                }
-            );
-            assert_eq!(index_map.len(), 2);
+            let mut insert_entry = |monitor_name: &str, git_index: u64, completed: bool, idle_time: Duration| {
+                index_map.insert(
+                    BuildInfoIndex { monitor_name: monitor_name.to_string(), key: BuildKey::Modification(git_index) },
+                    BuildInfoEntry {
+                        failed: false, completed, stalled: false, superseded: false, passed_stages: vec![],
+                        slack_timestamp: "test".to_string(),
+                        last_update_time: Utc::now() - idle_time, last_stage: "test".to_string(),
+                        message_text: "test".to_string(),
+                    }
+                );
+            };
+            insert_entry("test", 1, false, Duration::hours(1));
+            insert_entry("test", 2, false, Duration::days(1));
+            insert_entry("patient", 3, false, Duration::days(1));
+            insert_entry("patient", 4, true, Duration::days(1));
+            insert_entry("patient", 5, false, Duration::days(MAX_ENTRY_IDLE_DAYS));
+            assert_eq!(index_map.len(), 5);
         }
         manager.clear_old_message_entries();
         let index_map = manager.message_index.lock().unwrap();
-        assert_eq!(index_map.len(), 1);
+        let mut remaining: Vec<BuildKey> = index_map.keys().map(|index| index.key.clone()).collect();
+        remaining.sort();
+        assert_eq!(remaining, vec![BuildKey::Modification(1), BuildKey::Modification(3)]);
+    }
+
+    #[test]
+    fn test_merge_fallback_entry() {
+        let manager = test_manager();
+        let monitor = &manager.info_monitors[0];
+        let fallback_index = BuildInfoIndex {
+            monitor_name: monitor.name.clone(),
+            key: BuildKey::Pipeline { pipeline_name: "Delorean_Build".to_string(), counter: 12 },
+        };
+        let index = BuildInfoIndex { monitor_name: monitor.name.clone(), key: BuildKey::Modification(1955) };
+        manager.message_index.lock().unwrap().insert(fallback_index.clone(), BuildInfoEntry {
+            failed: false, completed: false, stalled: false, superseded: false,
+            passed_stages: vec!["Delorean_Build/Compile".to_string()], slack_timestamp: "1355517523.000005".to_string(),
+            last_update_time: Utc::now(), last_stage: "Delorean_Build/Compile".to_string(),
+            message_text: "test".to_string(),
+        });
+        manager.pinned_statuses.lock().unwrap().entry(monitor.name.clone()).or_default()
+            .builds.push_front((fallback_index.key.clone(), "test".to_string()));
+
+        let commit_time = Utc.ymd(2019, 8, 14).and_hms(14, 38, 20);
+        manager.build_history.lock().unwrap().add(BuildRecord {
+            monitor_name: monitor.name.clone(),
+            build_key: fallback_index.key.clone(),
+            pipeline_name: "Delorean_Build".to_string(),
+            build_step: "Compile".to_string(),
+            failed: false,
+            time: Utc::now(),
+            commit_time: None,
+        });
+
+        assert!(manager.first_failure_of_build(&fallback_index));
+
+        manager.merge_fallback_entry(monitor, &fallback_index, &index, Some(commit_time));
+        assert!(!manager.first_failure_of_build(&index));
+        assert!(manager.build_history.lock().unwrap().fallback_keys().is_empty());
+        assert!(manager.build_history.lock().unwrap().first_record_time(&monitor.name, &index.key).is_some());
+        let message_index = manager.message_index.lock().unwrap();
+        assert!(!message_index.contains_key(&fallback_index));
+        assert_eq!(message_index[&index].slack_timestamp, "1355517523.000005");
+        assert_eq!(manager.pinned_statuses.lock().unwrap()[&monitor.name].builds[0].0, index.key);
+    }
+
+    #[test]
+    fn test_first_failure_of_build() {
+        let manager = test_manager();
+        let index = |modification_id| BuildInfoIndex {
+            monitor_name: "Delorean".to_string(),
+            key: BuildKey::Modification(modification_id),
+        };
+        assert!(manager.first_failure_of_build(&index(1955)));
+        assert!(!manager.first_failure_of_build(&index(1955)));
+        assert!(manager.first_failure_of_build(&index(1956)));
+        manager.authors_notified.lock().unwrap().insert(index(1954), Utc::now() - Duration::days(MAX_ENTRY_IDLE_DAYS));
+        manager.clear_old_message_entries();
+        assert!(manager.first_failure_of_build(&index(1954)));
+        assert!(!manager.first_failure_of_build(&index(1955)));
+    }
+
+    #[test]
+    fn test_first_delivery() {
+        let manager = test_manager();
+        assert!(manager.first_delivery("Ev0PV52K21"));
+        assert!(!manager.first_delivery("Ev0PV52K21"));
+        for i in 0..RECENT_EVENT_LIMIT {
+            assert!(manager.first_delivery(&format!("Ev{}", i)));
+        }
+        assert!(manager.first_delivery("Ev0PV52K21"));
+    }
+
+    #[test]
+    fn test_queue_depth_metrics() {
+        let manager = test_manager();
+        let event = |result| StageEvent {
+            pipeline_name: "Delorean_Build".to_string(),
+            pipeline_counter: 1433,
+            stage_name: "Test".to_string(),
+            stage_counter: 1,
+            result,
+            timestamp: Utc::now(),
+            details: Default::default(),
+        };
+        manager.new_build_message(&event(StageResult::Failed));
+        manager.new_build_message(&event(StageResult::Passed));
+        manager.new_build_message(&event(StageResult::Building));
+        manager.first_failure_of_build(&BuildInfoIndex {
+            monitor_name: "Delorean".to_string(),
+            key: BuildKey::Modification(1955),
+        });
+        manager.quiet_hour_buffers.lock().unwrap().entry("Delorean".to_string()).or_default()
+            .insert(BuildKey::Modification(1955), "Delorean_Build/Test failed".to_string());
+        let output = manager.render_metrics();
+        assert!(output.contains("\nbuild_bot_pending_stage_events 2\n"), "{}", output);
+        assert!(output.contains("\nbuild_bot_quiet_hour_buffered_builds 1\n"));
+        assert!(output.contains("\nbuild_bot_authors_notified_size 1\n"));
+
+        //Once the worker is gone nothing can drain the queue, so dropped events aren't counted
+        drop(manager.take_event_receiver());
+        manager.new_build_message(&event(StageResult::Failed));
+        assert!(manager.render_metrics().contains("\nbuild_bot_pending_stage_events 2\n"));
+    }
+
+    #[test]
+    fn test_build_key_ordering() {
+        let value_stream = |counter| BuildKey::ValueStream { pipeline_name: "Delorean_Build".to_string(), counter };
+        assert!(BuildKey::Modification(12).is_before(&BuildKey::Modification(13)));
+        assert!(!BuildKey::Modification(13).is_before(&BuildKey::Modification(13)));
+        assert!(value_stream(7).is_before(&value_stream(8)));
+        assert!(!BuildKey::ValueStream { pipeline_name: "Zeus_Build".to_string(), counter: 7 }
+            .is_before(&value_stream(8)));
+        assert!(!BuildKey::Revision("8d1e2c4a".to_string()).is_before(&BuildKey::Revision("77aa01ee".to_string())));
+        assert!(!BuildKey::Pipeline { pipeline_name: "Delorean_Build".to_string(), counter: 7 }
+            .is_before(&BuildKey::Modification(13)));
+    }
+
+    #[test]
+    fn test_build_key() {
+        let mut manager = test_manager();
+        let history_item = HistoryItem {
+            counter: 1432, id: 1955, modified_time: None, revision: Some("8d1e2c4a".to_string()), author: None,
+            author_emails: vec![], label: None,
+        };
+        let details = StageDetails { label: Some("1432".to_string()), ..StageDetails::default() };
+        let cases = vec![
+            (Grouping::ModificationId, Some(&history_item), None, Some(BuildKey::Modification(1955))),
+            (Grouping::Revision, Some(&history_item), None, Some(BuildKey::Revision("8d1e2c4a".to_string()))),
+            (Grouping::PipelineLabel, Some(&history_item), None, None),
+            //Without GoCD, the notification's label is still enough to group by
+            (Grouping::ModificationId, None, Some(&details), None),
+            (Grouping::Revision, None, Some(&details), None),
+            (Grouping::PipelineLabel, None, Some(&details), Some(BuildKey::Label("1432".to_string()))),
+        ];
+        for (grouping, history_item, details, expected) in cases {
+            manager.info_monitors[0].grouping = grouping;
+            assert_eq!(manager.build_key(&manager.info_monitors[0], "Delorean_Build", 1432, history_item, details),
+                expected, "Grouping by {:?}", grouping);
+        }
+    }
+
+    #[test]
+    fn test_find_value_stream_origin() {
+        let monitors = vec![
+            BuildInfoMonitor { value_stream_fan_in: true, ..test_monitor("Delorean") },
+            test_monitor("Zeus"),
+        ];
+        let pipelines = |names: &[(&str, u64)]| names.iter()
+            .map(|(name, counter)| (name.to_string(), *counter))
+            .collect::<Vec<(String, u64)>>();
+        let origin = find_value_stream_origin(&monitors, "Staging_Deploy",
+            pipelines(&[("Delorean_Build", 1433), ("Staging_Deploy", 88)]));
+        assert_eq!(origin.map(|(monitor, name, counter)| (monitor.name.as_str(), name, counter)),
+            Some(("Delorean", "Delorean_Build".to_string(), 1433)));
+        //Only fan-in monitors pick up other pipelines
+        assert!(find_value_stream_origin(&monitors, "Olympus_Deploy",
+            pipelines(&[("Zeus_Build", 20), ("Olympus_Deploy", 4)])).is_none());
+        //A pipeline with nothing upstream, like a shared library build that triggers Delorean_Build, is left alone
+        assert!(find_value_stream_origin(&monitors, "Shared_Lib", pipelines(&[("Shared_Lib", 7)])).is_none());
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let quiet_hours = QuietHours {
+            start: NaiveTime::from_hms(22, 0, 0),
+            end: NaiveTime::from_hms(7, 0, 0),
+            timezone: chrono_tz::America::New_York,
+            critical_stages: vec!["Delorean_Deploy".to_string(), "Delorean_Build/Test".to_string()],
+        };
+        assert!(quiet_hours.is_active(Utc.ymd(2019, 8, 14).and_hms(3, 0, 0)));
+        assert!(quiet_hours.is_active(Utc.ymd(2019, 8, 14).and_hms(10, 59, 0)));
+        assert!(!quiet_hours.is_active(Utc.ymd(2019, 8, 14).and_hms(11, 0, 0)));
+        assert!(!quiet_hours.is_active(Utc.ymd(2019, 8, 14).and_hms(1, 59, 0)));
+        //Standard time in the winter, when 02:30 UTC is 21:30 in New York
+        assert!(!quiet_hours.is_active(Utc.ymd(2019, 1, 14).and_hms(2, 30, 0)));
+        assert!(quiet_hours.is_active(Utc.ymd(2019, 1, 14).and_hms(3, 0, 0)));
+        assert!(!quiet_hours.is_active(Utc.ymd(2019, 1, 14).and_hms(12, 0, 0)));
+        assert!(quiet_hours.is_critical("Delorean_Deploy", "Production"));
+        assert!(quiet_hours.is_critical("Delorean_Build", "Test"));
+        assert!(!quiet_hours.is_critical("Delorean_Build", "Compile"));
     }
 }
 
 impl AcceptBuildInfo for BuildInfoManager {
-    fn new_build_message(&self, stage_name: &str, build_num: u64, build_step: &str, pass_fail: &str) {
-        if let Some(monitor) = self.info_monitors.iter().find(|im| stage_name.starts_with(&im.filter_prefix)) {
-            match self.gocd_talker.get_history(stage_name) {
-                Err(err_str) => error!("Error getting GoCD Info: {}", err_str),
-                Ok(history_vec) => {
-                    match history_vec.iter().find(|hv| hv.counter == build_num) {
-                        None => error!("Could not find build info in GoCD!"),
-                        Some(history_item) => {
-                            let index = BuildInfoIndex {
-                                monitor_name: monitor.name.clone(),
-                                git_index: history_item.id,
-                            };
-                            let failed = pass_fail == "failed";
-                            info!("Handling build message for {}", &stage_name);
-                            let message_text = &format!("GoCD Build for {} has reached step {} on {} and {}",
-                                   &monitor.name, &build_step, &stage_name, &pass_fail);
-                            self.process_build_message(index, &message_text, &monitor.post_channel, failed);
-                        }
-                    }
-                }
-            }
+    fn new_build_message(&self, event: &StageEvent) {
+        match event.result {
+            StageResult::Passed | StageResult::Failed | StageResult::Cancelled => (),
+            StageResult::Building | StageResult::Unknown => {
+                info!("Ignoring {} event for {}/{}", event.result.name(), event.pipeline_name, event.stage_name);
+                return;
+            },
+        }
+        //Counted before it's sent so the worker can never finish with it first
+        self.pending_events.fetch_add(1, Ordering::SeqCst);
+        if self.event_sender.lock().unwrap().send(event.clone()).is_err() {
+            self.pending_events.fetch_sub(1, Ordering::SeqCst);
+            error!("The event worker has stopped, dropping {}/{}", event.pipeline_name, event.stage_name);
         }
-        self.clear_old_message_entries();
     }
 
+    fn set_author_dm_opt_out(&self, user_id: &str, opted_out: bool) {
+        info!("Setting failure DM opt out for {} to {}", user_id, opted_out);
+        self.store.set_author_dm_opt_out(user_id, opted_out);
+        let reply = if opted_out {
+            "Ok, I won't message you about failed builds any more. Reply \"start\" if you change your mind."
+        } else {
+            "Ok, I'll message you when a build with your commit fails."
+        };
+        self.send_direct_message(user_id, reply);
+    }
 }
 
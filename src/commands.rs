@@ -0,0 +1,97 @@
+use crate::store::SubscriptionEvents;
+
+pub const COMMAND_HELP: &str = "Usage:\n\
+    `/build subscribe <monitor or pipeline> [all|failures|passes]` get a DM when a build there does something\n\
+    `/build unsubscribe <monitor or pipeline>` stop getting those DMs\n\
+    `/build list` see what you're subscribed to\n\
+    `/build history <pipeline>` see the latest runs of a pipeline\n\
+    `/build trigger <pipeline>` start a new run of a pipeline\n\
+    `/build rerun <pipeline>/<counter>/<stage>` run a stage again";
+
+#[derive(Debug, PartialEq)]
+pub enum BuildCommand {
+    Subscribe { target: String, events: SubscriptionEvents },
+    Unsubscribe { target: String },
+    List,
+    History { pipeline_name: String },
+    Trigger { pipeline_name: String },
+    Rerun { pipeline_name: String, counter: u64, stage_name: String },
+    Help,
+}
+
+//Parses the text after the slash command itself, so for `/build subscribe Delorean failures` this gets
+//"subscribe Delorean failures"
+pub fn parse_command(text: &str) -> Result<BuildCommand, String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    match words.as_slice() {
+        [] | ["help"] => Ok(BuildCommand::Help),
+        ["list"] => Ok(BuildCommand::List),
+        ["subscribe", target] => Ok(BuildCommand::Subscribe {
+            target: target.to_string(),
+            events: SubscriptionEvents::All,
+        }),
+        ["subscribe", target, events] => Ok(BuildCommand::Subscribe {
+            target: target.to_string(),
+            events: parse_events(events)?,
+        }),
+        ["unsubscribe", target] => Ok(BuildCommand::Unsubscribe { target: target.to_string() }),
+        ["history", pipeline_name] => Ok(BuildCommand::History { pipeline_name: pipeline_name.to_string() }),
+        ["trigger", pipeline_name] => Ok(BuildCommand::Trigger { pipeline_name: pipeline_name.to_string() }),
+        ["rerun", stage] => parse_stage(stage),
+        _ => Err(format!("Sorry, I don't understand '{}'.\n{}", text.trim(), COMMAND_HELP)),
+    }
+}
+
+//Takes the stage the way it shows up in build messages, with or without the run number on the end
+fn parse_stage(stage: &str) -> Result<BuildCommand, String> {
+    match stage.split('/').collect::<Vec<&str>>().as_slice() {
+        [pipeline_name, counter, stage_name] | [pipeline_name, counter, stage_name, _] => Ok(BuildCommand::Rerun {
+            pipeline_name: pipeline_name.to_string(),
+            counter: counter.parse().map_err(|_| format!("'{}' isn't a pipeline counter", counter))?,
+            stage_name: stage_name.to_string(),
+        }),
+        _ => Err(format!("Expected a stage like Delorean_Build/1433/Test, not '{}'", stage)),
+    }
+}
+
+fn parse_events(events: &str) -> Result<SubscriptionEvents, String> {
+    match events.to_lowercase().as_str() {
+        "all" => Ok(SubscriptionEvents::All),
+        "failures" | "failed" => Ok(SubscriptionEvents::Failures),
+        "passes" | "passed" => Ok(SubscriptionEvents::Passes),
+        _ => Err(format!("Unknown event type '{}', expected one of all, failures, passes", events)),
+    }
+}
+
+#[cfg(test)]
+mod command_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        let cases = vec![
+            ("", Ok(BuildCommand::Help)),
+            ("list", Ok(BuildCommand::List)),
+            ("subscribe Delorean failures", Ok(BuildCommand::Subscribe {
+                target: "Delorean".to_string(), events: SubscriptionEvents::Failures })),
+            ("subscribe  Delorean_Build", Ok(BuildCommand::Subscribe {
+                target: "Delorean_Build".to_string(), events: SubscriptionEvents::All })),
+            ("unsubscribe Delorean", Ok(BuildCommand::Unsubscribe { target: "Delorean".to_string() })),
+            ("history Delorean_Build", Ok(BuildCommand::History { pipeline_name: "Delorean_Build".to_string() })),
+            ("trigger Delorean_Build", Ok(BuildCommand::Trigger { pipeline_name: "Delorean_Build".to_string() })),
+            ("rerun Delorean_Build/1433/Test", Ok(BuildCommand::Rerun {
+                pipeline_name: "Delorean_Build".to_string(), counter: 1433, stage_name: "Test".to_string() })),
+            ("rerun Delorean_Build/1433/Test/2", Ok(BuildCommand::Rerun {
+                pipeline_name: "Delorean_Build".to_string(), counter: 1433, stage_name: "Test".to_string() })),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse_command(text), expected, "Parsing '{}'", text);
+        }
+        assert!(parse_command("subscribe Delorean sometimes").unwrap_err().starts_with("Unknown event type 'sometimes'"));
+        assert_eq!(parse_command("rerun Delorean_Build/latest/Test"),
+            Err("'latest' isn't a pipeline counter".to_string()));
+        assert_eq!(parse_command("rerun Delorean_Build"),
+            Err("Expected a stage like Delorean_Build/1433/Test, not 'Delorean_Build'".to_string()));
+        assert!(parse_command("make coffee").unwrap_err().starts_with("Sorry, I don't understand 'make coffee'"));
+    }
+}
@@ -0,0 +1,82 @@
+use regex::Regex;
+
+//Slack cuts messages off at 4000 characters, this leaves room for the code fences and a heading
+const MAX_EXCERPT_CHARS: usize = 3500;
+
+//Which part of a failed job's console log to show. Lines matching one of the error patterns are picked out if
+//there are any, otherwise it's just the tail of the log, which is usually where the failure is.
+pub struct LogExcerptPolicy {
+    pub max_lines: usize,
+    pub error_patterns: Vec<Regex>,
+}
+
+impl LogExcerptPolicy {
+    pub fn new(max_lines: usize, error_patterns: &[String]) -> Result<LogExcerptPolicy, String> {
+        if max_lines == 0 {
+            return Err("A log excerpt's max_lines has to be more than 0".to_string());
+        }
+        let error_patterns = error_patterns.iter()
+            .map(|pattern| Regex::new(pattern)
+                .map_err(|e| format!("Bad log excerpt error pattern '{}': {}", pattern, e)))
+            .collect::<Result<Vec<Regex>, String>>()?;
+        Ok(LogExcerptPolicy { max_lines, error_patterns })
+    }
+
+    pub fn excerpt(&self, console_log: &str) -> String {
+        let lines: Vec<&str> = console_log.lines().filter(|line| !line.trim().is_empty()).collect();
+        let matching: Vec<&str> = lines.iter()
+            .filter(|line| self.error_patterns.iter().any(|pattern| pattern.is_match(line)))
+            .cloned()
+            .collect();
+        let chosen = if matching.is_empty() { &lines } else { &matching };
+        let excerpt = chosen[chosen.len().saturating_sub(self.max_lines)..].join("\n");
+        truncate_start(&excerpt, MAX_EXCERPT_CHARS)
+    }
+}
+
+//Keeps the end of the text since that's closest to where things went wrong
+fn truncate_start(text: &str, max_chars: usize) -> String {
+    let char_count = text.chars().count();
+    if char_count <= max_chars {
+        return text.to_string();
+    }
+    let kept: String = text.chars().skip(char_count - max_chars).collect();
+    format!("…{}", kept)
+}
+
+#[cfg(test)]
+mod console_log_tests {
+    use super::*;
+
+    const LOG: &str = "Compiling delorean v0.1.0\n\
+        test flux::capacitor ... ok\n\
+        test flux::charge ... FAILED\n\
+        \n\
+        error: test failed, to rerun pass '--lib'\n\
+        [go] Task: cargo test returned: 101";
+
+    #[test]
+    fn test_excerpt() {
+        let tail = LogExcerptPolicy { max_lines: 2, error_patterns: vec![] };
+        assert_eq!(tail.excerpt(LOG), "error: test failed, to rerun pass '--lib'\n[go] Task: cargo test returned: 101");
+
+        let patterns = LogExcerptPolicy {
+            max_lines: 10,
+            error_patterns: vec![Regex::new("FAILED").unwrap(), Regex::new("^error").unwrap()],
+        };
+        assert_eq!(patterns.excerpt(LOG), "test flux::charge ... FAILED\nerror: test failed, to rerun pass '--lib'");
+
+        let no_match = LogExcerptPolicy::new(1, &["panicked".to_string()]).unwrap();
+        assert_eq!(no_match.excerpt(LOG), "[go] Task: cargo test returned: 101");
+
+        assert!(LogExcerptPolicy::new(5, &["(unclosed".to_string()]).err().unwrap()
+            .starts_with("Bad log excerpt error pattern '(unclosed'"));
+        assert!(LogExcerptPolicy::new(0, &[]).is_err());
+    }
+
+    #[test]
+    fn test_truncate_start() {
+        assert_eq!(truncate_start("short", 10), "short");
+        assert_eq!(truncate_start("0123456789", 4), "…6789");
+    }
+}
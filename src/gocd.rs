@@ -1,67 +1,639 @@
-use reqwest::header;
-use reqwest::header::{ACCEPT, AUTHORIZATION};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
 use std::io::Read;
-use std::time::Duration;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use chrono::prelude::*;
+use reqwest::{RequestBuilder, StatusCode};
+use reqwest::header::{ACCEPT, AUTHORIZATION, RETRY_AFTER};
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+
+const GOCD_BASE_URL: &str = "https://gocd.imedidata.com:8154/go";
+
+#[derive(Debug, PartialEq)]
+pub enum GoCDError {
+    Network(String),
+    Auth,
+    NotFound,
+    Parse(String),
+    //Seconds GoCD asked us to wait, if it said
+    RateLimited(Option<u64>),
+}
+
+impl fmt::Display for GoCDError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GoCDError::Network(error) => write!(f, "Request Error: {}", error),
+            GoCDError::Auth => write!(f, "GoCD rejected our credentials"),
+            GoCDError::NotFound => write!(f, "Not found in GoCD"),
+            GoCDError::Parse(error) => write!(f, "JSON parse error: {}", error),
+            GoCDError::RateLimited(Some(seconds)) => write!(f, "Rate limited by GoCD for {}s", seconds),
+            GoCDError::RateLimited(None) => write!(f, "Rate limited by GoCD"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GoCDCredentials {
+    //Base64 of "username:password", which is what GOCD_TOKEN has always held
+    Encoded(String),
+    UsernamePassword { username: String, password: String },
+    //A GoCD personal access token
+    AccessToken(String),
+    //A JSON file with either "access_token" or "username" and "password", read again whenever it changes so the
+    //credentials can be rotated without a restart
+    File(String),
+}
+
+impl GoCDCredentials {
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            GoCDCredentials::Encoded(encoded) => request.header(AUTHORIZATION, format!("Basic {}", encoded)),
+            GoCDCredentials::UsernamePassword { username, password } => request.basic_auth(username, Some(password)),
+            GoCDCredentials::AccessToken(token) => request.bearer_auth(token),
+            //Credentials files never point at other files
+            GoCDCredentials::File(_) => request,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CredentialsFile {
+    access_token: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+fn read_credentials_file(path: &str) -> Result<GoCDCredentials, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read GoCD credentials file {}: {}", path, e))?;
+    let file: CredentialsFile = serde_json::from_str(&contents)
+        .map_err(|e| format!("Unable to parse GoCD credentials file {}: {}", path, e))?;
+    match file {
+        CredentialsFile { access_token: Some(token), .. } => Ok(GoCDCredentials::AccessToken(token)),
+        CredentialsFile { username: Some(username), password: Some(password), .. } =>
+            Ok(GoCDCredentials::UsernamePassword { username, password }),
+        _ => Err(format!("GoCD credentials file {} needs access_token or username and password", path)),
+    }
+}
+
+//Keeps whatever was loaded last if the file has gone missing or been left half written
+fn reload_if_changed(path: &str, loaded: &mut Option<(SystemTime, GoCDCredentials)>) -> Result<(), String> {
+    let modified = fs::metadata(path).and_then(|metadata| metadata.modified())
+        .map_err(|e| format!("Unable to check GoCD credentials file {}: {}", path, e))?;
+    if loaded.as_ref().map(|(loaded_modified, _)| *loaded_modified) == Some(modified) {
+        return Ok(());
+    }
+    let credentials = read_credentials_file(path)?;
+    info!("Loaded GoCD credentials from {}", path);
+    *loaded = Some((modified, credentials));
+    Ok(())
+}
 
 pub struct GoCDInfo {
-    client: reqwest::Client
+    client: reqwest::Client,
+    //For console logs and artifacts, which can take far longer to download than an API call
+    artifact_client: reqwest::Client,
+    credentials: GoCDCredentials,
+    //The credentials last read from a credentials file, with the file's modified time when they were read
+    file_credentials: Mutex<Option<(SystemTime, GoCDCredentials)>>,
 }
 
 impl GoCDInfo {
-    pub fn create(auth_str: &str) -> GoCDInfo {
+    pub fn create(credentials: GoCDCredentials) -> GoCDInfo {
         let cert = read_cert();
-        let mut headers = header::HeaderMap::new();
-        headers.insert(
-            AUTHORIZATION,
-            http::header::HeaderValue::from_str(&format!("Basic {}", auth_str)).unwrap()
-        );
         let client = reqwest::Client::builder()
-            .default_headers(headers)
-            .add_root_certificate(cert)
+            .add_root_certificate(cert.clone())
             .danger_accept_invalid_hostnames(true)
             .timeout(Duration::from_secs(1))
             .build().unwrap();
-        GoCDInfo { client }
+        let artifact_client = reqwest::Client::builder()
+            .add_root_certificate(cert)
+            .danger_accept_invalid_hostnames(true)
+            .timeout(Duration::from_secs(30))
+            .build().unwrap();
+        GoCDInfo { client, artifact_client, credentials, file_credentials: Mutex::new(None) }
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.credentials {
+            GoCDCredentials::File(path) => {
+                let mut file_credentials = self.file_credentials.lock().unwrap();
+                if let Err(error) = reload_if_changed(path, &mut file_credentials) {
+                    error!("{}", error);
+                }
+                match &*file_credentials {
+                    Some((_, credentials)) => credentials.authorize(request),
+                    None => request,
+                }
+            },
+            credentials => credentials.authorize(request),
+        }
+    }
+
+    //Where the credentials come from, for the health report. A credentials file that can't be read counts as failed
+    //even when we still have what it held before, since the next rotation won't be picked up.
+    pub fn credentials_status(&self) -> Result<String, String> {
+        match &self.credentials {
+            GoCDCredentials::File(path) => {
+                reload_if_changed(path, &mut self.file_credentials.lock().unwrap())?;
+                Ok(format!("credentials file {}", path))
+            },
+            GoCDCredentials::Encoded(_) => Ok("GOCD_TOKEN".to_string()),
+            GoCDCredentials::UsernamePassword { .. } => Ok("username and password".to_string()),
+            GoCDCredentials::AccessToken(_) => Ok("personal access token".to_string()),
+        }
+    }
+
+    //Who GoCD thinks we are, which is about the cheapest call that still needs our credentials to be accepted
+    pub fn get_current_user(&self) -> Result<String, GoCDError> {
+        let url = format!("{}/api/current_user", GOCD_BASE_URL);
+        let user: CurrentUser = self.get_json(&url, Some("application/vnd.go.cd.v1+json"))?;
+        Ok(user.login_name)
+    }
+
+    //The latest runs of a pipeline, newest first
+    pub fn get_history(&self, pipeline_name: &str) -> Result<Vec<HistoryItem>, GoCDError> {
+        let url = format!("{}/api/pipelines/{}/history", GOCD_BASE_URL, pipeline_name);
+        let history: PipelineHistory = self.get_json(&url, Some("application/vnd.go.cd.v6+json"))?;
+        Ok(history.history_items())
+    }
+
+    pub fn get_pipeline_instance(&self, pipeline_name: &str, counter: u64) -> Result<PipelineInstance, GoCDError> {
+        let url = format!("{}/api/pipelines/{}/instance/{}", GOCD_BASE_URL, pipeline_name, counter);
+        self.get_json(&url, Some("application/vnd.go.cd.v1+json"))
+    }
+
+    pub fn get_stage(&self, pipeline_name: &str, counter: u64, stage_name: &str, stage_counter: u64)
+    -> Result<Stage, GoCDError> {
+        let url = format!("{}/api/stages/{}/{}/{}/{}", GOCD_BASE_URL, pipeline_name, counter, stage_name,
+            stage_counter);
+        self.get_json(&url, Some("application/vnd.go.cd.v2+json"))
+    }
+
+    pub fn get_job(&self, pipeline_name: &str, counter: u64, stage_name: &str, stage_counter: u64, job_name: &str)
+    -> Result<Job, GoCDError> {
+        let url = format!("{}/api/jobs/{}/{}/{}/{}/{}", GOCD_BASE_URL, pipeline_name, counter, stage_name,
+            stage_counter, job_name);
+        self.get_json(&url, Some("application/vnd.go.cd.v1+json"))
+    }
+
+    //Triggers a new instance of the pipeline with its latest materials, returns GoCD's confirmation message
+    pub fn schedule_pipeline(&self, pipeline_name: &str) -> Result<String, GoCDError> {
+        let url = format!("{}/api/pipelines/{}/schedule", GOCD_BASE_URL, pipeline_name);
+        self.post(&url, "application/vnd.go.cd.v1+json")
+    }
+
+    pub fn run_stage(&self, pipeline_name: &str, counter: u64, stage_name: &str) -> Result<String, GoCDError> {
+        let url = format!("{}/api/stages/{}/{}/{}/run", GOCD_BASE_URL, pipeline_name, counter, stage_name);
+        self.post(&url, "application/vnd.go.cd.v2+json")
+    }
+
+    pub fn get_console_log(&self, pipeline_name: &str, counter: u64, stage_name: &str, stage_counter: u64,
+                           job_name: &str) -> Result<String, GoCDError> {
+        let url = format!("{}/files/{}/{}/{}/{}/{}/cruise-output/console.log", GOCD_BASE_URL, pipeline_name, counter,
+            stage_name, stage_counter, job_name);
+        self.download(&url)
+    }
+
+    //Urls of every file a job published as an artifact under the given folder
+    pub fn get_artifact_urls(&self, pipeline_name: &str, counter: u64, stage_name: &str, stage_counter: u64,
+                             job_name: &str, folder: &str) -> Result<Vec<String>, GoCDError> {
+        let url = format!("{}/files/{}/{}/{}/{}/{}.json", GOCD_BASE_URL, pipeline_name, counter, stage_name,
+            stage_counter, job_name);
+        let entries: Vec<ArtifactEntry> = self.get_json(&url, None)?;
+        Ok(artifact_urls(&entries, folder))
+    }
+
+    pub fn get_artifact(&self, url: &str) -> Result<String, GoCDError> {
+        self.download(url)
+    }
+
+    //The pipeline instances upstream of the given one in its value stream map, furthest first and ending with the
+    //given one. The first is the root, which is what every pipeline triggered by the same commit has in common.
+    pub fn get_value_stream(&self, pipeline_name: &str, counter: u64) -> Result<Vec<(String, u64)>, GoCDError> {
+        let url = format!("{}/pipelines/value_stream_map/{}/{}.json", GOCD_BASE_URL, pipeline_name, counter);
+        let value_stream_map: ValueStreamMap = self.get_json(&url, None)?;
+        Ok(value_stream_map.upstream_pipelines())
+    }
+
+    fn get_text(&self, url: &str, accept: Option<&str>) -> Result<String, GoCDError> {
+        let mut request = self.authorize(self.client.get(url));
+        if let Some(accept) = accept {
+            request = request.header(ACCEPT, accept);
+        }
+        read_response(request.send().map_err(|e| GoCDError::Network(e.to_string()))?)
+    }
+
+    fn download(&self, url: &str) -> Result<String, GoCDError> {
+        let request = self.authorize(self.artifact_client.get(url));
+        read_response(request.send().map_err(|e| GoCDError::Network(e.to_string()))?)
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, url: &str, accept: Option<&str>) -> Result<T, GoCDError> {
+        parse_json(&self.get_text(url, accept)?)
+    }
+
+    fn post(&self, url: &str, accept: &str) -> Result<String, GoCDError> {
+        let response = self.authorize(self.client.post(url))
+            .header(ACCEPT, accept)
+            .header("X-GoCD-Confirm", "true")
+            .send().map_err(|e| GoCDError::Network(e.to_string()))?;
+        let message: MessageResponse = parse_json(&read_response(response)?)?;
+        Ok(message.message)
+    }
+}
+
+fn read_response(mut response: reqwest::Response) -> Result<String, GoCDError> {
+    let retry_after = response.headers().get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    check_status(response.status(), retry_after)?;
+    response.text().map_err(|e| GoCDError::Network(e.to_string()))
+}
+
+fn check_status(status: StatusCode, retry_after: Option<u64>) -> Result<(), GoCDError> {
+    match status {
+        status if status.is_success() => Ok(()),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(GoCDError::Auth),
+        StatusCode::NOT_FOUND => Err(GoCDError::NotFound),
+        StatusCode::TOO_MANY_REQUESTS => Err(GoCDError::RateLimited(retry_after)),
+        status => Err(GoCDError::Network(format!("GoCD responded {}", status))),
+    }
+}
+
+fn parse_json<T: DeserializeOwned>(text: &str) -> Result<T, GoCDError> {
+    serde_json::from_str(text).map_err(|e| GoCDError::Parse(e.to_string()))
+}
+
+//GoCD sends stage counters as strings from some APIs and numbers from others
+fn counter_from_string_or_number<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Counter {
+        Number(u64),
+        Text(String),
+    }
+    match serde::Deserialize::deserialize(deserializer)? {
+        Counter::Number(counter) => Ok(counter),
+        Counter::Text(counter) => counter.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+#[derive(Deserialize)]
+struct PipelineHistory {
+    pipelines: Vec<PipelineInstance>,
+}
+
+impl PipelineHistory {
+    //Runs without a modification to go on, which GoCD has for pipelines that were never triggered, are left out
+    fn history_items(&self) -> Vec<HistoryItem> {
+        self.pipelines.iter().filter_map(HistoryItem::from_instance).collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PipelineInstance {
+    pub counter: u64,
+    pub label: Option<String>,
+    pub build_cause: BuildCause,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BuildCause {
+    #[serde(default)]
+    pub material_revisions: Vec<MaterialRevision>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MaterialRevision {
+    pub material: Material,
+    #[serde(default)]
+    pub modifications: Vec<Modification>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Material {
+    #[serde(rename = "type")]
+    pub material_type: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Modification {
+    pub id: u64,
+    pub revision: Option<String>,
+    //Milliseconds since the epoch
+    pub modified_time: Option<i64>,
+    pub user_name: Option<String>,
+    pub email_address: Option<String>,
+}
+
+impl Modification {
+    //Git materials put the committer in user_name as "Name <email>", email_address is usually empty
+    fn author_email(&self) -> Option<String> {
+        self.email_address.clone()
+            .filter(|e| !e.is_empty())
+            .or_else(|| self.user_name.as_ref().and_then(|a| email_from_user_name(a)))
     }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Stage {
+    #[serde(deserialize_with = "counter_from_string_or_number")]
+    pub counter: u64,
+    #[serde(default)]
+    pub jobs: Vec<Job>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Job {
+    pub name: String,
+    pub result: Option<String>,
+    //Only the job API fills these in, they're empty in a stage's list of jobs
+    #[serde(default)]
+    pub job_state_transitions: Vec<JobStateTransition>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JobStateTransition {
+    //Milliseconds since the epoch
+    pub state_change_time: i64,
+}
+
+impl Job {
+    pub fn failed(&self) -> bool {
+        self.result.as_deref() == Some("Failed")
+    }
+
+    //From when the job was scheduled to its last change of state
+    pub fn run_time(&self) -> Option<time::Duration> {
+        let first = self.job_state_transitions.first()?.state_change_time;
+        let last = self.job_state_transitions.last()?.state_change_time;
+        Some(time::Duration::milliseconds(last - first))
+    }
+}
+
+#[derive(Deserialize)]
+struct CurrentUser {
+    login_name: String,
+}
+
+#[derive(Deserialize)]
+struct MessageResponse {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct ArtifactEntry {
+    name: String,
+    #[serde(rename = "type")]
+    entry_type: String,
+    url: Option<String>,
+    #[serde(default)]
+    files: Vec<ArtifactEntry>,
+}
+
+impl ArtifactEntry {
+    fn collect_file_urls(&self, urls: &mut Vec<String>) {
+        match (self.entry_type.as_str(), &self.url) {
+            ("file", Some(url)) => urls.push(url.clone()),
+            _ => for file in &self.files {
+                file.collect_file_urls(urls);
+            },
+        }
+    }
+}
 
-    pub fn get_history(&self, pipeline_name: &str) -> Result<Vec<HistoryItem>, String> {
-        let url = format!("https://gocd.imedidata.com:8154/go/api/pipelines/{}/history", pipeline_name);
-        let response: serde_json::Value = self.client.get(&url)
-            .header(ACCEPT, "application/vnd.go.cd.v6+json")
-            .send().map_err(|e| format!("Request Error: {}", e))?
-            .json().map_err(|e| format!("JSON parse error: {}", e))?;
-        let pipelines_json_array = response.get("pipelines")
-            .and_then(|p| p.as_array())
-            .ok_or("Invalid Json")?;
-        Ok(pipelines_json_array.iter().filter_map(|p| HistoryItem::from_json(&p)).collect())
+fn artifact_urls(entries: &[ArtifactEntry], folder: &str) -> Vec<String> {
+    let mut urls = vec![];
+    for entry in entries.iter().filter(|entry| entry.name == folder) {
+        entry.collect_file_urls(&mut urls);
     }
+    urls
+}
+
+#[derive(Deserialize)]
+struct ValueStreamMap {
+    current_pipeline: String,
+    levels: Vec<ValueStreamLevel>,
+}
+
+#[derive(Deserialize)]
+struct ValueStreamLevel {
+    nodes: Vec<ValueStreamNode>,
+}
+
+#[derive(Deserialize)]
+struct ValueStreamNode {
+    id: String,
+    name: String,
+    node_type: String,
+    #[serde(default)]
+    parents: Vec<String>,
+    #[serde(default)]
+    instances: Vec<ValueStreamInstance>,
+}
+
+//Material nodes have revisions rather than counters
+#[derive(Deserialize)]
+struct ValueStreamInstance {
+    counter: Option<u64>,
+}
+
+impl ValueStreamMap {
+    //The map has everything downstream of the current pipeline as well, so only nodes it can be reached from by
+    //following parents count. Parents are always on an earlier level, so one pass back through the levels finds them.
+    fn upstream_pipelines(&self) -> Vec<(String, u64)> {
+        let mut upstream_ids: HashSet<&str> = HashSet::new();
+        upstream_ids.insert(&self.current_pipeline);
+        for node in self.levels.iter().rev().flat_map(|level| &level.nodes) {
+            if upstream_ids.contains(node.id.as_str()) {
+                upstream_ids.extend(node.parents.iter().map(|parent| parent.as_str()));
+            }
+        }
+        self.levels.iter()
+            .flat_map(|level| &level.nodes)
+            .filter(|node| node.node_type == "PIPELINE" && upstream_ids.contains(node.id.as_str()))
+            .filter_map(|node| node.instances.first()
+                .and_then(|instance| instance.counter)
+                .map(|counter| (node.name.clone(), counter)))
+            .collect()
+    }
+}
+
+pub fn pipeline_url(pipeline_name: &str, counter: u64) -> String {
+    format!("{}/pipelines/value_stream_map/{}/{}", GOCD_BASE_URL, pipeline_name, counter)
 }
 
 #[derive(Debug)]
 pub struct HistoryItem {
     pub counter: u64,
     pub id: u64,
+    pub modified_time: Option<DateTime<Utc>>,
+    pub revision: Option<String>,
+    pub author: Option<String>,
+    pub author_emails: Vec<String>,
+    pub label: Option<String>,
 }
 
 impl HistoryItem {
-    fn from_json(json_obj: &serde_json::Value) -> Option<HistoryItem> {
-        let maybe_id = json_obj.pointer("/build_cause/material_revisions/0/modifications/0/id")
-            .and_then(|id| id.as_u64());
-        let maybe_counter = json_obj.get("counter").and_then(|c| c.as_u64());
-        if maybe_id.is_some() && maybe_counter.is_some() {
-            return Some(HistoryItem {
-                counter: maybe_counter.unwrap(),
-                id: maybe_id.unwrap()
-            })
-        }
-        else {
-            return None
+    pub fn from_instance(instance: &PipelineInstance) -> Option<HistoryItem> {
+        let modification = instance.build_cause.material_revisions.first()?.modifications.first()?;
+        //GoCD can batch several commits, possibly by different people, into one run. Pipeline materials are upstream
+        //runs rather than commits, so they don't have anyone to tell.
+        let mut author_emails: Vec<String> = vec![];
+        let emails = instance.build_cause.material_revisions.iter()
+            .filter(|material_revision| material_revision.material.material_type != "Pipeline")
+            .flat_map(|material_revision| &material_revision.modifications)
+            .filter_map(|modification| modification.author_email());
+        for email in emails {
+            if !author_emails.contains(&email) {
+                author_emails.push(email);
+            }
         }
+        Some(HistoryItem {
+            counter: instance.counter,
+            id: modification.id,
+            modified_time: modification.modified_time.map(|millis| Utc.timestamp_millis(millis)),
+            revision: modification.revision.clone(),
+            author: modification.user_name.clone(),
+            author_emails,
+            label: instance.label.clone(),
+        })
     }
 }
 
+pub fn email_from_user_name(user_name: &str) -> Option<String> {
+    let start = user_name.find('<')?;
+    let end = user_name[start..].find('>')?;
+    Some(user_name[start + 1..start + end].to_string()).filter(|e| e.contains('@'))
+}
+
 fn read_cert() -> reqwest::Certificate {
     let mut cert_buff = vec![];
     std::fs::File::open("gocd_cert.pem").unwrap().read_to_end(&mut cert_buff).unwrap();
     reqwest::Certificate::from_pem(&cert_buff).unwrap()
 }
+
+#[cfg(test)]
+mod gocd_tests {
+    use super::*;
+
+    #[test]
+    fn test_history_items() {
+        let history: PipelineHistory = parse_json(include_str!("../test_fixtures/gocd/pipeline_history.json")).unwrap();
+        let history_items = history.history_items();
+        assert_eq!(history.pipelines.len(), 3);
+        assert_eq!(history_items.len(), 2);
+        assert_eq!(history_items[0].counter, 1433);
+        assert_eq!(history_items[0].id, 1955);
+        assert_eq!(history_items[0].label.as_deref(), Some("1433"));
+        assert_eq!(history_items[0].author_emails, vec!["mmcfly@mdsol.com".to_string()]);
+        assert_eq!(history_items[0].modified_time, Some(Utc.ymd(2019, 8, 14).and_hms(14, 38, 20)));
+        assert_eq!(history_items[1].author_emails, vec!["ebrown@mdsol.com".to_string()]);
+    }
+
+    #[test]
+    fn test_stage_and_job() {
+        let instance: PipelineInstance = parse_json(include_str!("../test_fixtures/gocd/pipeline_instance.json"))
+            .unwrap();
+        let history_item = HistoryItem::from_instance(&instance).unwrap();
+        assert_eq!(history_item.id, 1955);
+        assert_eq!(history_item.author_emails, vec!["mmcfly@mdsol.com".to_string(), "jparker@mdsol.com".to_string()]);
+
+        let stage: Stage = parse_json(include_str!("../test_fixtures/gocd/stage.json")).unwrap();
+        assert_eq!(stage.counter, 2);
+        let failed_jobs: Vec<&str> = stage.jobs.iter().filter(|job| job.failed()).map(|job| job.name.as_str())
+            .collect();
+        assert_eq!(failed_jobs, vec!["unit"]);
+        assert!(stage.jobs[0].run_time().is_none());
+        let stage: Stage = parse_json(r#"{"name": "Test", "counter": "3", "jobs": []}"#).unwrap();
+        assert_eq!(stage.counter, 3);
+
+        let job: Job = parse_json(include_str!("../test_fixtures/gocd/job.json")).unwrap();
+        assert!(job.failed());
+        assert_eq!(job.run_time(), Some(time::Duration::seconds(590)));
+    }
+
+    #[test]
+    fn test_artifact_urls() {
+        let entries: Vec<ArtifactEntry> = parse_json(include_str!("../test_fixtures/gocd/artifacts.json")).unwrap();
+        assert_eq!(artifact_urls(&entries, "test-reports"), vec![
+            "https://gocd.imedidata.com:8154/go/files/Delorean_Build/1433/Test/2/unit/test-reports/unit/TEST-Flux.xml",
+            "https://gocd.imedidata.com:8154/go/files/Delorean_Build/1433/Test/2/unit/test-reports/TEST-Clock.xml",
+        ]);
+        assert!(artifact_urls(&entries, "coverage").is_empty());
+    }
+
+    #[test]
+    fn test_value_stream_pipelines() {
+        let mut value_stream_map: ValueStreamMap =
+            parse_json(include_str!("../test_fixtures/gocd/value_stream_map.json")).unwrap();
+        assert_eq!(value_stream_map.upstream_pipelines(),
+            vec![("Delorean_Build".to_string(), 1433), ("Staging_Deploy".to_string(), 88)]);
+        //Staging_Deploy is downstream of Delorean_Build, so it isn't part of where Delorean_Build came from
+        value_stream_map.current_pipeline = "Delorean_Build".to_string();
+        assert_eq!(value_stream_map.upstream_pipelines(), vec![("Delorean_Build".to_string(), 1433)]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(check_status(StatusCode::OK, None), Ok(()));
+        assert_eq!(check_status(StatusCode::UNAUTHORIZED, None), Err(GoCDError::Auth));
+        assert_eq!(check_status(StatusCode::NOT_FOUND, None), Err(GoCDError::NotFound));
+        assert_eq!(check_status(StatusCode::TOO_MANY_REQUESTS, Some(30)), Err(GoCDError::RateLimited(Some(30))));
+        assert!(matches!(check_status(StatusCode::BAD_GATEWAY, None), Err(GoCDError::Network(_))));
+        assert!(matches!(parse_json::<PipelineInstance>("<html>Please sign in</html>"), Err(GoCDError::Parse(_))));
+        let message: MessageResponse = parse_json(include_str!("../test_fixtures/gocd/schedule.json")).unwrap();
+        assert_eq!(message.message, "Request to schedule pipeline Delorean_Build accepted");
+        let user: CurrentUser = parse_json(include_str!("../test_fixtures/gocd/current_user.json")).unwrap();
+        assert_eq!(user.login_name, "build_bot");
+    }
+
+    #[test]
+    fn test_authorize() {
+        let authorization = |credentials: GoCDCredentials| {
+            let request = credentials.authorize(reqwest::Client::new().get(GOCD_BASE_URL)).build().unwrap();
+            request.headers().get(AUTHORIZATION).map(|value| value.to_str().unwrap().to_string())
+        };
+        assert_eq!(authorization(GoCDCredentials::Encoded("bWFydHk6b3V0YXRpbWU=".to_string())),
+            Some("Basic bWFydHk6b3V0YXRpbWU=".to_string()));
+        assert_eq!(authorization(GoCDCredentials::UsernamePassword {
+            username: "marty".to_string(),
+            password: "outatime".to_string(),
+        }), Some("Basic bWFydHk6b3V0YXRpbWU=".to_string()));
+        assert_eq!(authorization(GoCDCredentials::AccessToken("2f8a1c".to_string())),
+            Some("Bearer 2f8a1c".to_string()));
+    }
+
+    #[test]
+    fn test_reload_credentials_file() {
+        let path = std::env::temp_dir().join(format!("gocd_credentials_test_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut loaded = None;
+        assert!(reload_if_changed(path, &mut loaded).is_err());
+        assert!(loaded.is_none());
+
+        fs::write(path, r#"{"access_token": "2f8a1c"}"#).unwrap();
+        reload_if_changed(path, &mut loaded).unwrap();
+        assert_eq!(loaded.as_ref().map(|(_, credentials)| credentials),
+            Some(&GoCDCredentials::AccessToken("2f8a1c".to_string())));
+
+        //Pretend it was loaded before the file was rotated
+        fs::write(path, r#"{"username": "marty", "password": "outatime"}"#).unwrap();
+        loaded.as_mut().unwrap().0 = SystemTime::UNIX_EPOCH;
+        reload_if_changed(path, &mut loaded).unwrap();
+        assert_eq!(loaded.as_ref().map(|(_, credentials)| credentials), Some(&GoCDCredentials::UsernamePassword {
+            username: "marty".to_string(),
+            password: "outatime".to_string(),
+        }));
+
+        fs::write(path, r#"{"username": "marty"}"#).unwrap();
+        loaded.as_mut().unwrap().0 = SystemTime::UNIX_EPOCH;
+        assert!(reload_if_changed(path, &mut loaded).is_err());
+        assert!(matches!(loaded, Some((_, GoCDCredentials::UsernamePassword { .. }))));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt::Debug;
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+use serde_json::{Value, json};
+use time::Duration;
+
+#[derive(Debug, PartialEq)]
+pub enum HealthStatus {
+    Ok,
+    Degraded,
+    Down,
+}
+
+impl HealthStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HealthStatus::Ok => "ok",
+            HealthStatus::Degraded => "degraded",
+            HealthStatus::Down => "down",
+        }
+    }
+}
+
+#[derive(Default)]
+struct ServiceHealth {
+    last_success: Option<DateTime<Utc>>,
+    last_error: Option<(DateTime<Utc>, String)>,
+}
+
+impl ServiceHealth {
+    fn is_failing(&self) -> bool {
+        match (&self.last_success, &self.last_error) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(success_time), Some((error_time, _))) => error_time > success_time,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "last_success": self.last_success.map(|time| time.to_rfc3339()),
+            "last_error": self.last_error.as_ref().map(|(time, _)| time.to_rfc3339()),
+            "last_error_message": self.last_error.as_ref().map(|(_, message)| message),
+        })
+    }
+}
+
+type SlackAuthCheck = (DateTime<Utc>, Result<String, String>);
+
+//What asking GoCD who we are found. Rejected credentials mean nothing that needs GoCD can work until someone fixes
+//them, but when GoCD can't be reached the bot rides it out by grouping builds on what's in the notifications.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GoCDAuthCheck {
+    Authenticated(String),
+    Rejected(String),
+    Unreachable(String),
+}
+
+impl GoCDAuthCheck {
+    fn to_json(&self) -> Value {
+        match self {
+            GoCDAuthCheck::Authenticated(user) => json!({"ok": true, "user": user}),
+            GoCDAuthCheck::Rejected(error) => json!({"ok": false, "rejected": true, "error": error}),
+            GoCDAuthCheck::Unreachable(error) => json!({"ok": false, "rejected": false, "error": error}),
+        }
+    }
+}
+
+//How one piece of config loaded: where it came from, or why the bot is running without it
+pub type ConfigLoad = Result<String, String>;
+
+pub struct HealthTracker {
+    gocd: Mutex<ServiceHealth>,
+    slack: Mutex<ServiceHealth>,
+    slack_auth: Mutex<Option<SlackAuthCheck>>,
+    gocd_auth: Mutex<Option<(DateTime<Utc>, GoCDAuthCheck)>>,
+}
+
+//Checks that call out to another service are only rerun once a minute no matter how often we get polled
+fn cached<T: Clone>(last_check: &Mutex<Option<(DateTime<Utc>, T)>>, check: impl FnOnce() -> T) -> T {
+    let mut last_check = last_check.lock().unwrap();
+    match &*last_check {
+        Some((checked_time, result)) if Utc::now().signed_duration_since(*checked_time) < Duration::minutes(1) =>
+            result.clone(),
+        _ => {
+            let result = check();
+            *last_check = Some((Utc::now(), result.clone()));
+            result
+        }
+    }
+}
+
+impl HealthTracker {
+    pub fn new() -> HealthTracker {
+        HealthTracker {
+            gocd: Mutex::new(ServiceHealth::default()),
+            slack: Mutex::new(ServiceHealth::default()),
+            slack_auth: Mutex::new(None),
+            gocd_auth: Mutex::new(None),
+        }
+    }
+
+    pub fn record<T, E: Debug>(&self, service: &str, result: &Result<T, E>) {
+        let mut service_health = match service {
+            "gocd" => self.gocd.lock().unwrap(),
+            _ => self.slack.lock().unwrap(),
+        };
+        match result {
+            Ok(_) => service_health.last_success = Some(Utc::now()),
+            Err(error) => service_health.last_error = Some((Utc::now(), format!("{:?}", error))),
+        }
+    }
+
+    //auth.test is rate limited, so it's only called once a minute
+    pub fn check_slack_auth(&self, auth_test: impl FnOnce() -> Result<String, String>) -> Result<String, String> {
+        cached(&self.slack_auth, auth_test)
+    }
+
+    //Checked on its own so an idle bot still notices GoCD rejecting it, rather than waiting for a build to find out
+    pub fn check_gocd_auth(&self, auth_test: impl FnOnce() -> GoCDAuthCheck) -> GoCDAuthCheck {
+        cached(&self.gocd_auth, auth_test)
+    }
+
+    //Down, which takes the instance out of the load balancer, is for when Slack or GoCD won't accept our credentials
+    //since no amount of waiting fixes that. GoCD being unreachable, recent failed calls to either and config that
+    //didn't load are degraded, since the bot still posts messages with what it has.
+    pub fn report(&self, slack_auth: &Result<String, String>, gocd_auth: &GoCDAuthCheck, config: &[(&str, ConfigLoad)])
+    -> (HealthStatus, Value) {
+        let gocd = self.gocd.lock().unwrap();
+        let slack = self.slack.lock().unwrap();
+        let config_failed = config.iter().any(|(_, load)| load.is_err());
+        let status = match (slack_auth, gocd_auth) {
+            (Err(_), _) | (_, GoCDAuthCheck::Rejected(_)) => HealthStatus::Down,
+            (_, GoCDAuthCheck::Unreachable(_)) => HealthStatus::Degraded,
+            _ if gocd.is_failing() || slack.is_failing() || config_failed => HealthStatus::Degraded,
+            _ => HealthStatus::Ok,
+        };
+        let auth_json = match slack_auth {
+            Ok(user) => json!({"ok": true, "user": user}),
+            Err(error) => json!({"ok": false, "error": error}),
+        };
+        let config_json: serde_json::Map<String, Value> = config.iter()
+            .map(|(name, load)| (name.to_string(), match load {
+                Ok(source) => json!({"loaded": true, "source": source}),
+                Err(error) => json!({"loaded": false, "error": error}),
+            }))
+            .collect();
+        let mut slack_json = slack.to_json();
+        slack_json["auth_test"] = auth_json;
+        let mut gocd_json = gocd.to_json();
+        gocd_json["auth_check"] = gocd_auth.to_json();
+        let body = json!({
+            "status": status.name(),
+            "version": env!("CARGO_PKG_VERSION"),
+            "config": config_json,
+            "gocd": gocd_json,
+            "slack": slack_json,
+        });
+        (status, body)
+    }
+}
+
+#[cfg(test)]
+mod health_tests {
+    use super::*;
+
+    #[test]
+    fn test_report_status() {
+        let tracker = HealthTracker::new();
+        let authed = Ok("bot".to_string());
+        let gocd_authed = GoCDAuthCheck::Authenticated("build_bot".to_string());
+        let config = vec![("store", Ok("bot_store.json".to_string()))];
+        assert_eq!(tracker.report(&authed, &gocd_authed, &config).0, HealthStatus::Ok);
+        tracker.record::<(), _>("gocd", &Err("certificate expired"));
+        assert_eq!(tracker.report(&authed, &gocd_authed, &config).0, HealthStatus::Degraded);
+        tracker.record::<_, String>("gocd", &Ok(()));
+        assert_eq!(tracker.report(&authed, &gocd_authed, &config).0, HealthStatus::Ok);
+        assert_eq!(tracker.report(&Err("token_revoked".to_string()), &gocd_authed, &config).0, HealthStatus::Down);
+        let unreachable = GoCDAuthCheck::Unreachable("connection refused".to_string());
+        assert_eq!(tracker.report(&authed, &unreachable, &config).0, HealthStatus::Degraded);
+        let rejected = GoCDAuthCheck::Rejected("401 Unauthorized".to_string());
+        let (status, body) = tracker.report(&authed, &rejected, &config);
+        assert_eq!(status, HealthStatus::Down);
+        assert_eq!(body["gocd"]["auth_check"], json!({"ok": false, "rejected": true, "error": "401 Unauthorized"}));
+
+        let config = vec![("store", Ok("bot_store.json".to_string())),
+            ("user_mapping", Err("Unable to parse user mapping file users.json".to_string()))];
+        let (status, body) = tracker.report(&authed, &gocd_authed, &config);
+        assert_eq!(status, HealthStatus::Degraded);
+        assert_eq!(body["config"]["store"], json!({"loaded": true, "source": "bot_store.json"}));
+        assert_eq!(body["config"]["user_mapping"]["loaded"], json!(false));
+        assert_eq!(body["gocd"]["auth_check"]["user"], json!("build_bot"));
+    }
+
+    #[test]
+    fn test_gocd_auth_cached() {
+        let tracker = HealthTracker::new();
+        let rejected = tracker.check_gocd_auth(|| GoCDAuthCheck::Rejected("401 Unauthorized".to_string()));
+        assert_eq!(rejected, GoCDAuthCheck::Rejected("401 Unauthorized".to_string()));
+        let again = tracker.check_gocd_auth(|| panic!("GoCD shouldn't be asked again within a minute"));
+        assert_eq!(again, rejected);
+    }
+}
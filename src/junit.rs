@@ -0,0 +1,133 @@
+use regex::Regex;
+use serde_derive::Deserialize;
+
+//Which stages publish JUnit reports, as pipeline or pipeline/step, and the artifact folder they're uploaded to
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestReportPolicy {
+    pub stages: Vec<String>,
+    pub artifact_folder: String,
+}
+
+impl TestReportPolicy {
+    pub fn covers(&self, stage_name: &str, build_step: &str) -> bool {
+        let stage_key = format!("{}/{}", stage_name, build_step);
+        self.stages.iter().any(|stage| *stage == stage_name || *stage == stage_key)
+    }
+}
+
+//Counts from one or more JUnit XML reports. Only <testcase> elements are looked at, rather than the totals on
+//<testsuite>, since not every tool that writes these fills the totals in.
+#[derive(Debug, Default, PartialEq)]
+pub struct TestSummary {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub failing_tests: Vec<String>,
+}
+
+//The patterns for picking test cases out of a report, compiled once and reused for every report
+pub struct ReportParser {
+    testcase_regex: Regex,
+    name_regex: Regex,
+    classname_regex: Regex,
+}
+
+impl ReportParser {
+    pub fn new() -> ReportParser {
+        ReportParser {
+            testcase_regex: Regex::new(r"(?s)<testcase\b([^>]*?)(?:/>|>(.*?)</testcase>)").unwrap(),
+            name_regex: attribute_regex("name"),
+            classname_regex: attribute_regex("classname"),
+        }
+    }
+}
+
+impl TestSummary {
+    pub fn add_report(&mut self, parser: &ReportParser, xml: &str) {
+        for captures in parser.testcase_regex.captures_iter(xml) {
+            let attributes = captures.get(1).map(|a| a.as_str()).unwrap_or("");
+            let body = captures.get(2).map(|b| b.as_str()).unwrap_or("");
+            if body.contains("<failure") || body.contains("<error") {
+                self.failed += 1;
+                let name = attribute(&parser.name_regex, attributes).unwrap_or_default();
+                self.failing_tests.push(match attribute(&parser.classname_regex, attributes) {
+                    Some(class_name) => format!("{}.{}", class_name, name),
+                    None => name,
+                });
+            } else if body.contains("<skipped") {
+                self.skipped += 1;
+            } else {
+                self.passed += 1;
+            }
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.passed + self.failed + self.skipped
+    }
+
+    pub fn summary_line(&self) -> String {
+        format!("Tests: {} passed, {} failed, {} skipped", self.passed, self.failed, self.skipped)
+    }
+
+    pub fn failing_tests_text(&self, limit: usize) -> String {
+        let mut lines: Vec<String> = self.failing_tests.iter().take(limit).map(|name| format!("• {}", name)).collect();
+        if self.failing_tests.len() > limit {
+            lines.push(format!("…and {} more", self.failing_tests.len() - limit));
+        }
+        format!("Failing tests:\n{}", lines.join("\n"))
+    }
+}
+
+fn attribute_regex(name: &str) -> Regex {
+    Regex::new(&format!(r#"\b{}\s*=\s*"([^"]*)""#, name)).unwrap()
+}
+
+fn attribute(attribute_regex: &Regex, attributes: &str) -> Option<String> {
+    attribute_regex.captures(attributes)
+        .and_then(|captures| captures.get(1))
+        .map(|value| unescape(value.as_str()))
+}
+
+fn unescape(value: &str) -> String {
+    value.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod junit_tests {
+    use super::*;
+
+    const REPORT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuite name="flux" tests="5">
+  <testcase classname="flux.CapacitorTest" name="charges" time="0.01"/>
+  <testcase classname="flux.CapacitorTest" name="holds &quot;1.21&quot; gigawatts">
+    <failure message="expected 1.21">assertion failed</failure>
+  </testcase>
+  <testcase classname="flux.CapacitorTest" name="discharges" time="0.2"></testcase>
+  <testcase classname="flux.ClockTest" name="strikes">
+    <skipped/>
+  </testcase>
+  <testcase name="boots">
+    <error type="NullPointerException"/>
+  </testcase>
+</testsuite>"#;
+
+    #[test]
+    fn test_add_report() {
+        let parser = ReportParser::new();
+        let mut summary = TestSummary::default();
+        summary.add_report(&parser, REPORT);
+        assert_eq!(summary, TestSummary {
+            passed: 2,
+            failed: 2,
+            skipped: 1,
+            failing_tests: vec!["flux.CapacitorTest.holds \"1.21\" gigawatts".to_string(), "boots".to_string()],
+        });
+        summary.add_report(&parser, r#"<testsuite><testcase classname="a" name="b"/></testsuite>"#);
+        assert_eq!(summary.summary_line(), "Tests: 3 passed, 2 failed, 1 skipped");
+        assert_eq!(summary.total(), 6);
+        assert_eq!(summary.failing_tests_text(1), "Failing tests:\n• flux.CapacitorTest.holds \"1.21\" gigawatts\n…and 1 more");
+    }
+}
@@ -3,10 +3,13 @@
 #[macro_use]
 extern crate log;
 
+use std::collections::HashMap;
 use std::env;
 use std::str::FromStr;
+use std::sync::Arc;
 use rocket::*;
 use rocket::http::*;
+use rocket::response::{content, status};
 use serde_json::{Value, json};
 use rocket_contrib::json::Json;
 use regex::Regex;
@@ -14,18 +17,49 @@ use ring::digest::SHA256;
 use ring::hmac::VerificationKey;
 
 mod slack;
-use crate::slack::{SlackParams, handle_event_object, get_regex_string, VerifiedSlackJson};
+use crate::slack::{SlackParams, handle_event_object, VerifiedSlackJson, VerifiedSlackCommand};
+
+mod parser;
+use crate::parser::{title_regex_string, change_regex_string};
 
 mod gocd;
+use crate::gocd::GoCDCredentials;
 
 mod build_info_manager;
-use crate::build_info_manager::{BuildInfoManager};
+use crate::build_info_manager::{BuildInfoManager, MonitorSettings};
+
+mod build_history;
+
+mod scheduler;
+
+mod metrics;
+
+mod health;
+use crate::health::HealthStatus;
+
+mod template;
+
+mod store;
+use crate::store::BotStore;
+
+mod user_directory;
+use crate::user_directory::UserDirectory;
+
+mod commands;
+use crate::commands::parse_command;
+
+mod console_log;
+
+mod junit;
+
+mod monitor_config;
+use crate::monitor_config::load_monitor_configs;
 
 #[cfg(test)]
 mod test;
 
 #[post("/event", data = "<message_map>")]
-fn message_receive(message_map: VerifiedSlackJson, slack_params: State<SlackParams>, collector: State<BuildInfoManager>)
+fn message_receive(message_map: VerifiedSlackJson, slack_params: State<SlackParams>, collector: State<Arc<BuildInfoManager>>)
 -> Result<Json<Value>, Status> {
     let map_obj = message_map.json_obj();
     match map_obj.get("type").and_then(|type_val| type_val.as_str()) {
@@ -34,9 +68,15 @@ fn message_receive(message_map: VerifiedSlackJson, slack_params: State<SlackPara
             .and_then(|challenge_str| Some(Ok(Json(json!({"challenge": challenge_str})))))
             .unwrap_or_else(|| Err(Status::BadRequest)),
         Some("event_callback") => {
+            if let Some(event_id) = map_obj.get("event_id").and_then(|id| id.as_str()) {
+                if !collector.first_delivery(event_id) {
+                    info!("Skipping retry {:?} of event {}", message_map.retry_num(), event_id);
+                    return Ok(Json(Value::Null));
+                }
+            }
             match map_obj.get("event") {
                 Some(Value::Object(event_obj)) =>
-                    handle_event_object(event_obj, &slack_params, collector.inner()).map_err(|e| {
+                    handle_event_object(event_obj, &slack_params, collector.inner().as_ref()).map_err(|e| {
                         info!("{}", e);
                         Status::BadRequest
                     }),
@@ -53,10 +93,59 @@ fn message_receive(message_map: VerifiedSlackJson, slack_params: State<SlackPara
     }
 }
 
+#[post("/command", data = "<command>")]
+fn slash_command(command: VerifiedSlackCommand, manager: State<Arc<BuildInfoManager>>) -> Json<Value> {
+    info!("Got command {} '{}' from {}", command.command, command.text, command.user_id);
+    let reply = match parse_command(&command.text) {
+        Ok(build_command) => manager.handle_command(&command.user_id, build_command),
+        Err(error) => error,
+    };
+    Json(json!({"response_type": "ephemeral", "text": reply}))
+}
 
 #[get("/app_status")]
-fn app_status() -> Status {
-    Status::Ok
+fn app_status(manager: State<Arc<BuildInfoManager>>) -> Json<Value> {
+    Json(manager.health_report().1)
+}
+
+#[get("/health")]
+fn health(manager: State<Arc<BuildInfoManager>>) -> status::Custom<Json<Value>> {
+    match manager.health_report() {
+        (HealthStatus::Down, body) => status::Custom(Status::ServiceUnavailable, Json(body)),
+        (_, body) => status::Custom(Status::Ok, Json(body)),
+    }
+}
+
+#[get("/flaky_stages?<monitor>")]
+fn flaky_stages(monitor: Option<String>, manager: State<Arc<BuildInfoManager>>) -> Json<Value> {
+    let stages: Vec<Value> = manager.flaky_stage_report(monitor.as_ref().map(|m| m.as_str()), 10).iter()
+        .map(|stage| json!({
+            "stage": stage.stage_key,
+            "flaky_builds": stage.flaky_builds,
+            "builds": stage.builds,
+            "score": stage.score(),
+        }))
+        .collect();
+    Json(json!({"flaky_stages": stages}))
+}
+
+#[get("/lead_times")]
+fn lead_times(manager: State<Arc<BuildInfoManager>>) -> Json<Value> {
+    let monitors: Vec<Value> = manager.lead_time_report().iter()
+        .map(|(monitor_name, percentiles)| json!({
+            "monitor": monitor_name,
+            "count": percentiles.count,
+            "p50_seconds": percentiles.p50.num_seconds(),
+            "p90_seconds": percentiles.p90.num_seconds(),
+            "p99_seconds": percentiles.p99.num_seconds(),
+        }))
+        .collect();
+    Json(json!({"lead_times": monitors}))
+}
+
+#[get("/metrics")]
+fn metrics(manager: State<Arc<BuildInfoManager>>) -> content::Plain<String> {
+    content::Plain(manager.render_metrics())
 }
 
 fn init_logging() {
@@ -77,7 +166,8 @@ impl SlackParams {
         fn get_env_var(name: &str) -> String {
             env::var(name).unwrap_or_else(|_| panic!("Unable to access env var {}", name))
         }
-        let regex = Regex::new(&get_regex_string()).unwrap();
+        let regex = Regex::new(&title_regex_string()).unwrap();
+        let change_regex = Regex::new(&change_regex_string()).unwrap();
         if is_prod {
             SlackParams {
                 verification_token: get_env_var("SLACK_VERIFICATION_TOKEN"),
@@ -88,7 +178,7 @@ impl SlackParams {
                 gocd_bod_id: get_env_var("GOCD_BOD_ID"),
                 instance_token: get_env_var("SLACK_INSTANCE_TOKEN"),
                 title_match_regex: regex,
-                gocd_token: get_env_var("GOCD_TOKEN"),
+                change_match_regex: change_regex,
             }
         }
         else {
@@ -101,21 +191,86 @@ impl SlackParams {
                 gocd_bod_id: "test".to_string(),
                 instance_token: "test".to_string(),
                 title_match_regex: regex,
-                gocd_token: "test".to_string(),
+                change_match_regex: change_regex,
             }
         }
     }
 }
 
+//A credentials file wins since it's the only one that can be rotated without a restart, then a personal access token,
+//then a username and password, then GOCD_TOKEN for setups that still have it base64 encoded already
+fn gocd_credentials_from_env() -> GoCDCredentials {
+    match (env::var("GOCD_CREDENTIALS_PATH"), env::var("GOCD_ACCESS_TOKEN"), env::var("GOCD_USERNAME"),
+           env::var("GOCD_PASSWORD")) {
+        (Ok(path), _, _, _) => GoCDCredentials::File(path),
+        (_, Ok(token), _, _) => GoCDCredentials::AccessToken(token),
+        (_, _, Ok(username), Ok(password)) => GoCDCredentials::UsernamePassword { username, password },
+        _ => GoCDCredentials::Encoded(env::var("GOCD_TOKEN").expect("Unable to access env var GOCD_TOKEN")),
+    }
+}
+
 fn main() {
     #[cfg(not(debug_assertions))]
     init_logging();
     let app = rocket::ignite();
     let is_prod = app.config().environment.is_prod();
     let slack_params = SlackParams::from_env(is_prod);
+    let mut config_status = vec![];
+    let store_path = env::var("BOT_STORE_PATH").unwrap_or_else(|_| "bot_store.json".to_string());
+    let store = match BotStore::load(&store_path) {
+        Ok(store) => {
+            config_status.push(("store", Ok(store_path)));
+            store
+        },
+        Err(error) => {
+            error!("{}, nothing will be saved until it's fixed", error);
+            config_status.push(("store", Err(error)));
+            BotStore::in_memory()
+        },
+    };
+    let user_mapping_path = env::var("SLACK_USER_MAPPING_PATH").ok();
+    let user_directory = match UserDirectory::load(user_mapping_path.clone()) {
+        Ok(user_directory) => {
+            let source = user_mapping_path.unwrap_or_else(|| "users.lookupByEmail only".to_string());
+            config_status.push(("user_mapping", Ok(source)));
+            user_directory
+        },
+        Err(error) => {
+            error!("{}, falling back to users.lookupByEmail", error);
+            config_status.push(("user_mapping", Err(error)));
+            UserDirectory::new(HashMap::new())
+        },
+    };
+    let gocd_credentials = if is_prod {
+        gocd_credentials_from_env()
+    } else {
+        GoCDCredentials::Encoded("test".to_string())
+    };
+    let monitor_configs = load_monitor_configs(env::var("MONITOR_CONFIG_PATH").ok())
+        .unwrap_or_else(|error| panic!("{}", error));
+    let mut monitor_settings = HashMap::new();
+    let mut custom_templates = vec![];
+    for (monitor_name, config) in monitor_configs {
+        if config.templates.is_some() {
+            custom_templates.push(monitor_name.clone());
+        }
+        let settings = MonitorSettings::from_config(config)
+            .unwrap_or_else(|error| panic!("Monitor {} has bad config: {}", monitor_name, error));
+        monitor_settings.insert(monitor_name, settings);
+    }
+    custom_templates.sort();
+    config_status.push(("templates", Ok(if custom_templates.is_empty() {
+        "defaults".to_string()
+    } else {
+        format!("custom for {}, defaults for the rest", custom_templates.join(", "))
+    })));
+    let manager = Arc::new(BuildInfoManager::new(&slack_params.instance_token, gocd_credentials, store,
+        user_directory, monitor_settings, config_status).unwrap_or_else(|error| panic!("{}", error)));
+    scheduler::start(manager.clone());
+    scheduler::start_event_worker(manager.clone());
     app
-        .mount("/", routes![message_receive, app_status])
-        .manage(BuildInfoManager::new(&slack_params.instance_token, &slack_params.gocd_token))
+        .mount("/", routes![message_receive, slash_command, app_status, health, flaky_stages, lead_times, metrics])
+        .manage(manager)
         .manage(slack_params)
         .launch();
 }
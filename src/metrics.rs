@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::build_history::LeadTimePercentiles;
+
+#[derive(Default)]
+struct TimingStat {
+    count: u64,
+    errors: u64,
+    total_seconds: f64,
+}
+
+pub struct Metrics {
+    stage_results: Mutex<BTreeMap<(String, String, String), u64>>,
+    stage_durations: Mutex<BTreeMap<(String, String), TimingStat>>,
+    api_calls: Mutex<BTreeMap<(&'static str, &'static str), TimingStat>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            stage_results: Mutex::new(BTreeMap::new()),
+            stage_durations: Mutex::new(BTreeMap::new()),
+            api_calls: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record_stage_result(&self, pipeline_name: &str, build_step: &str, result: &str) {
+        let key = (pipeline_name.to_string(), build_step.to_string(), result.to_string());
+        *self.stage_results.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+
+    pub fn record_stage_duration(&self, pipeline_name: &str, build_step: &str, duration: Duration) {
+        let mut stage_durations = self.stage_durations.lock().unwrap();
+        let stat = stage_durations.entry((pipeline_name.to_string(), build_step.to_string())).or_default();
+        stat.count += 1;
+        stat.total_seconds += duration.as_secs_f64();
+    }
+
+    pub fn time_call<T, E>(&self, service: &'static str, method: &'static str, call: impl FnOnce() -> Result<T, E>)
+    -> Result<T, E> {
+        let start = Instant::now();
+        let result = call();
+        let mut api_calls = self.api_calls.lock().unwrap();
+        let stat = api_calls.entry((service, method)).or_default();
+        stat.count += 1;
+        stat.total_seconds += start.elapsed().as_secs_f64();
+        if result.is_err() {
+            stat.errors += 1;
+        }
+        result
+    }
+
+    //Renders everything in the Prometheus text exposition format, with the gauges supplied by the caller since
+    //they live elsewhere
+    pub fn render(&self, gauges: &[(&str, &str, usize)]) -> String {
+        let mut output = String::new();
+
+        write_header(&mut output, "build_bot_stage_results_total", "Stage results reported by GoCD", "counter");
+        for ((pipeline, stage, result), count) in self.stage_results.lock().unwrap().iter() {
+            writeln!(output, "build_bot_stage_results_total{{pipeline=\"{}\",stage=\"{}\",result=\"{}\"}} {}",
+                escape_label(pipeline), escape_label(stage), escape_label(result), count).unwrap();
+        }
+
+        write_header(&mut output, "build_bot_stage_duration_seconds",
+            "Time between a stage's notification and the previous one for the same build", "summary");
+        for ((pipeline, stage), stat) in self.stage_durations.lock().unwrap().iter() {
+            let labels = format!("pipeline=\"{}\",stage=\"{}\"", escape_label(pipeline), escape_label(stage));
+            writeln!(output, "build_bot_stage_duration_seconds_sum{{{}}} {}", labels, stat.total_seconds).unwrap();
+            writeln!(output, "build_bot_stage_duration_seconds_count{{{}}} {}", labels, stat.count).unwrap();
+        }
+
+        let api_calls = self.api_calls.lock().unwrap();
+        write_header(&mut output, "build_bot_api_requests_total", "Requests made to GoCD and Slack", "counter");
+        for ((service, method), stat) in api_calls.iter() {
+            writeln!(output, "build_bot_api_requests_total{{service=\"{}\",method=\"{}\"}} {}",
+                service, method, stat.count).unwrap();
+        }
+        write_header(&mut output, "build_bot_api_errors_total", "Failed requests made to GoCD and Slack", "counter");
+        for ((service, method), stat) in api_calls.iter() {
+            writeln!(output, "build_bot_api_errors_total{{service=\"{}\",method=\"{}\"}} {}",
+                service, method, stat.errors).unwrap();
+        }
+        write_header(&mut output, "build_bot_api_request_duration_seconds",
+            "Latency of requests made to GoCD and Slack", "summary");
+        for ((service, method), stat) in api_calls.iter() {
+            let labels = format!("service=\"{}\",method=\"{}\"", service, method);
+            writeln!(output, "build_bot_api_request_duration_seconds_sum{{{}}} {}", labels, stat.total_seconds).unwrap();
+            writeln!(output, "build_bot_api_request_duration_seconds_count{{{}}} {}", labels, stat.count).unwrap();
+        }
+
+        for (name, help, value) in gauges {
+            write_header(&mut output, name, help, "gauge");
+            writeln!(output, "{} {}", name, value).unwrap();
+        }
+        output
+    }
+}
+
+pub fn render_lead_times(monitor_lead_times: &[(String, LeadTimePercentiles)]) -> String {
+    let mut output = String::new();
+    write_header(&mut output, "build_bot_lead_time_seconds",
+        "Time from commit until the monitor's final stage passed", "summary");
+    for (monitor_name, percentiles) in monitor_lead_times {
+        let monitor_label = escape_label(monitor_name);
+        for (quantile, lead_time) in &[("0.5", percentiles.p50), ("0.9", percentiles.p90), ("0.99", percentiles.p99)] {
+            writeln!(output, "build_bot_lead_time_seconds{{monitor=\"{}\",quantile=\"{}\"}} {}",
+                monitor_label, quantile, lead_time.num_seconds()).unwrap();
+        }
+        writeln!(output, "build_bot_lead_time_seconds_count{{monitor=\"{}\"}} {}", monitor_label, percentiles.count)
+            .unwrap();
+    }
+    output
+}
+
+fn write_header(output: &mut String, name: &str, help: &str, metric_type: &str) {
+    writeln!(output, "# HELP {} {}", name, help).unwrap();
+    writeln!(output, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record_stage_result("Delorean_Build", "Test", "failed");
+        metrics.record_stage_result("Delorean_Build", "Test", "failed");
+        metrics.record_stage_duration("Delorean_Build", "Test", Duration::from_secs(90));
+        let _ = metrics.time_call("gocd", "history", || Err::<(), _>("timeout"));
+        let output = metrics.render(&[("build_bot_message_index_size", "Builds being tracked", 3)]);
+        assert!(output.contains("build_bot_stage_results_total{pipeline=\"Delorean_Build\",stage=\"Test\",result=\"failed\"} 2"));
+        assert!(output.contains("build_bot_stage_duration_seconds_sum{pipeline=\"Delorean_Build\",stage=\"Test\"} 90"));
+        assert!(output.contains("build_bot_api_errors_total{service=\"gocd\",method=\"history\"} 1"));
+        assert!(output.contains("# TYPE build_bot_message_index_size gauge\nbuild_bot_message_index_size 3"));
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::fs;
+
+use serde_derive::Deserialize;
+
+use crate::template::TemplateConfig;
+use crate::junit::TestReportPolicy;
+use crate::build_info_manager::{FinalStage, Grouping, PinnedStatus};
+
+//Settings for one of the monitors in BuildInfoManager::new that can be changed without a rebuild. Anything left out
+//keeps the monitor's built in default.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MonitorConfig {
+    #[serde(default)]
+    pub templates: Option<TemplateConfig>,
+    //One of modification_id, revision, pipeline_label or value_stream_root
+    #[serde(default)]
+    pub grouping: Option<Grouping>,
+    #[serde(default)]
+    pub value_stream_fan_in: Option<bool>,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHoursConfig>,
+    //The pipeline_name and build_step that mean a build has gone all the way out, which lead times run up to
+    #[serde(default)]
+    pub final_stage: Option<FinalStage>,
+    //Needs a final_stage, since that's what tells a build that's finished from one that's stuck
+    #[serde(default)]
+    pub stall_policy: Option<StallPolicyConfig>,
+    #[serde(default)]
+    pub retention: Option<RetentionConfig>,
+    //A message per build, which is on unless it's turned off in favor of a pinned_status message
+    #[serde(default)]
+    pub per_build_messages: Option<bool>,
+    //One pinned message showing the latest_builds most recent builds
+    #[serde(default)]
+    pub pinned_status: Option<PinnedStatus>,
+    //Keeps each build's message to a short rollup, with every stage's full message as a reply under it
+    #[serde(default)]
+    pub threaded_details: Option<bool>,
+    #[serde(default)]
+    pub log_excerpt: Option<LogExcerptConfig>,
+    //The stages, as pipeline or pipeline/step, that upload JUnit reports to artifact_folder
+    #[serde(default)]
+    pub test_reports: Option<TestReportPolicy>,
+}
+
+//Start and end are "HH:MM" local times in the timezone, which is an IANA name like "America/New_York". Critical
+//stages are pipeline or pipeline/step.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHoursConfig {
+    pub start: String,
+    pub end: String,
+    pub timezone: String,
+    #[serde(default)]
+    pub critical_stages: Vec<String>,
+}
+
+//A build with no news for expected_duration_minutes is marked as stalled, with a reply in its thread if
+//thread_alert is set
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StallPolicyConfig {
+    pub expected_duration_minutes: u32,
+    #[serde(default)]
+    pub thread_alert: bool,
+}
+
+//How long a build's message keeps being updated after the last notification for it, 4 hours if it's left out. With
+//keep_until_final_stage, which needs a final_stage, builds are kept until they get there, for up to a week.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+    #[serde(default)]
+    pub idle_time_minutes: Option<u32>,
+    #[serde(default)]
+    pub keep_until_final_stage: bool,
+}
+
+//Failed jobs get up to max_lines of their console log posted under the build's message, picking out lines that match
+//one of the error_patterns regexes if any do
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogExcerptConfig {
+    pub max_lines: usize,
+    #[serde(default)]
+    pub error_patterns: Vec<String>,
+}
+
+//The config file is a JSON object of monitor name to its MonitorConfig
+pub fn load_monitor_configs(config_path: Option<String>) -> Result<HashMap<String, MonitorConfig>, String> {
+    match config_path {
+        Some(path) => {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("Unable to read monitor config file {}: {}", path, e))?;
+            serde_json::from_str(&contents).map_err(|e| format!("Unable to parse monitor config file {}: {}", path, e))
+        },
+        None => Ok(HashMap::new()),
+    }
+}
+
+#[cfg(test)]
+mod monitor_config_tests {
+    use super::*;
+
+    #[test]
+    fn test_load_monitor_configs() {
+        let path = std::env::temp_dir().join(format!("monitor_config_test_{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, r#"{"Delorean": {"templates": {"failed": ":boom: {{stage}}/{{step}} by {{author}}"}},
+            "Zeus": {"grouping": "pipeline_label",
+                "quiet_hours": {"start": "22:00", "end": "07:00", "timezone": "America/New_York"}}}"#).unwrap();
+        let configs = load_monitor_configs(Some(path.clone())).unwrap();
+        let templates = configs["Delorean"].templates.as_ref().unwrap();
+        assert_eq!(templates.failed, ":boom: {{stage}}/{{step}} by {{author}}");
+        assert!(templates.passed.starts_with("GoCD Build for {{monitor}}"));
+        assert!(configs["Zeus"].templates.is_none());
+        assert_eq!(configs["Zeus"].grouping, Some(Grouping::PipelineLabel));
+        assert_eq!(configs["Delorean"].grouping, None);
+        let quiet_hours = configs["Zeus"].quiet_hours.as_ref().unwrap();
+        assert_eq!((quiet_hours.start.as_str(), quiet_hours.timezone.as_str()), ("22:00", "America/New_York"));
+        assert!(quiet_hours.critical_stages.is_empty());
+
+        fs::write(&path, r#"{"Delorean": {"template": {}}}"#).unwrap();
+        let error = load_monitor_configs(Some(path.clone())).err().unwrap();
+        assert!(error.starts_with(&format!("Unable to parse monitor config file {}: unknown field `template`", path)),
+            "Got error '{}'", error);
+        fs::remove_file(&path).unwrap();
+        assert!(load_monitor_configs(None).unwrap().is_empty());
+    }
+}
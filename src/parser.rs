@@ -0,0 +1,227 @@
+use chrono::prelude::*;
+use regex::Regex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StageResult {
+    Building,
+    Passed,
+    Failed,
+    Cancelled,
+    Unknown,
+}
+
+impl StageResult {
+    //GoCD says fixed and broken when a stage passes or fails after doing the opposite last time, which doesn't
+    //matter to us
+    fn from_word(word: &str) -> StageResult {
+        match word.to_lowercase().as_str() {
+            "building" => StageResult::Building,
+            "passed" | "fixed" => StageResult::Passed,
+            "failed" | "broken" => StageResult::Failed,
+            "cancelled" | "canceled" => StageResult::Cancelled,
+            _ => StageResult::Unknown,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StageResult::Building => "building",
+            StageResult::Passed => "passed",
+            StageResult::Failed => "failed",
+            StageResult::Cancelled => "cancelled",
+            StageResult::Unknown => "unknown",
+        }
+    }
+}
+
+//One GoCD notification about a stage, pulled out of the title of the GoCD bot's attachment
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageEvent {
+    pub pipeline_name: String,
+    pub pipeline_counter: u64,
+    pub stage_name: String,
+    pub stage_counter: u64,
+    pub result: StageResult,
+    //When GoCD posted the notification to Slack
+    pub timestamp: DateTime<Utc>,
+    pub details: StageDetails,
+}
+
+//Whatever else the GoCD bot put in the attachment besides the title. It's all optional since what shows up depends
+//on the notifier's settings, but it means there's still a link and someone to credit when GoCD's API is down.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StageDetails {
+    pub gocd_url: Option<String>,
+    pub triggered_by: Option<String>,
+    pub label: Option<String>,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub revision: String,
+    pub author: String,
+}
+
+//Covers both the plain "Pipeline stage [a/1/b/1] passed" titles and the "Stage [a/1/b/1] is building" style, where
+//the status comes after is/has/was depending on which it is. Any other status word comes through as Unknown.
+pub fn title_regex_string() -> String {
+    concat!(r"^(?:Pipeline )?[Ss]tage \[(?P<pipeline_name>[\w\-.]+)/(?P<pipeline_counter>\d+)/",
+        r"(?P<stage_name>[\w\-.]+)/(?P<stage_counter>\d+)\]:? (?:(?:is|was|has(?: been)?) )?",
+        r"(?P<status>[A-Za-z]+)\b").to_string()
+}
+
+impl StageEvent {
+    //The stage name, plus which run it is when it's been rerun
+    pub fn stage_label(&self) -> String {
+        if self.stage_counter > 1 {
+            format!("{} (run {})", self.stage_name, self.stage_counter)
+        } else {
+            self.stage_name.clone()
+        }
+    }
+}
+
+pub fn parse_stage_event(title_regex: &Regex, title: &str, timestamp: DateTime<Utc>) -> Option<StageEvent> {
+    let captures = title_regex.captures(title.trim())?;
+    Some(StageEvent {
+        pipeline_name: captures.name("pipeline_name")?.as_str().to_string(),
+        pipeline_counter: captures.name("pipeline_counter")?.as_str().parse().ok()?,
+        stage_name: captures.name("stage_name")?.as_str().to_string(),
+        stage_counter: captures.name("stage_counter")?.as_str().parse().ok()?,
+        result: StageResult::from_word(captures.name("status")?.as_str()),
+        timestamp,
+        details: StageDetails::default(),
+    })
+}
+
+//Matches one line of a notification's changes, e.g. "git: <url> revision 8d1e2c4a, modified by Marty McFly on <date>"
+pub fn change_regex_string() -> String {
+    r"revision (?P<revision>\w+), modified by (?P<author>.+?)(?: on |$)".to_string()
+}
+
+//Fields come from the attachment's fields, and the text is read as more of them, one "Name: value" per line
+pub fn parse_stage_details(change_regex: &Regex, title_link: Option<&str>, text: Option<&str>, fields: &[(&str, &str)])
+    -> StageDetails {
+    let text_fields: Vec<(&str, &str)> = text.unwrap_or("").lines()
+        .filter_map(|line| {
+            let separator = line.find(':')?;
+            Some((line[..separator].trim(), line[separator + 1..].trim()))
+        })
+        .collect();
+    let all_fields: Vec<&(&str, &str)> = fields.iter().chain(text_fields.iter()).collect();
+    let field = |names: &[&str]| all_fields.iter()
+        .find(|(title, value)| !value.is_empty() && names.iter().any(|name| title.eq_ignore_ascii_case(name)))
+        .map(|(_, value)| value.to_string());
+
+    let changes = all_fields.iter()
+        .filter(|(title, _)| title.eq_ignore_ascii_case("changes") || title.eq_ignore_ascii_case("changeset"))
+        .flat_map(|(_, value)| value.lines())
+        .filter_map(|line| change_regex.captures(line))
+        .filter_map(|captures| Some(Change {
+            revision: captures.name("revision")?.as_str().to_string(),
+            author: captures.name("author")?.as_str().trim().to_string(),
+        }))
+        .collect();
+
+    StageDetails {
+        gocd_url: title_link.filter(|link| link.starts_with("http")).map(|link| link.to_string()),
+        triggered_by: field(&["Triggered by", "Trigger"]),
+        label: field(&["Label"]),
+        changes,
+    }
+}
+
+//Slack timestamps are seconds since the epoch with the message's sequence number after the decimal point
+pub fn parse_slack_timestamp(ts: &str) -> Option<DateTime<Utc>> {
+    let seconds = ts.split('.').next()?.parse().ok()?;
+    Some(Utc.timestamp(seconds, 0))
+}
+
+#[cfg(test)]
+mod parser_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stage_event() {
+        let title_regex = Regex::new(&title_regex_string()).unwrap();
+        let timestamp = Utc.ymd(2019, 8, 14).and_hms(9, 30, 0);
+        let cases = vec![
+            ("Pipeline stage [Zeus_ECS_Distro/20/Deploy/1] passed",
+                Some(("Zeus_ECS_Distro", 20, "Deploy", 1, StageResult::Passed))),
+            ("Pipeline stage [Delorean_Build/1432/Test/2] failed",
+                Some(("Delorean_Build", 1432, "Test", 2, StageResult::Failed))),
+            ("Stage [Delorean_Build/1433/Test/1] is building",
+                Some(("Delorean_Build", 1433, "Test", 1, StageResult::Building))),
+            ("Stage [Delorean_Deploy/88/Production/1] was cancelled",
+                Some(("Delorean_Deploy", 88, "Production", 1, StageResult::Cancelled))),
+            ("Stage [Delorean_Deploy/89/Production/2] has been cancelled",
+                Some(("Delorean_Deploy", 89, "Production", 2, StageResult::Cancelled))),
+            ("Stage [Delorean_Build/1434/Test/1] is fixed",
+                Some(("Delorean_Build", 1434, "Test", 1, StageResult::Passed))),
+            ("Stage [Delorean_Build/1435/Test/3] is broken",
+                Some(("Delorean_Build", 1435, "Test", 3, StageResult::Failed))),
+            ("Stage [delorean-api.build/7/unit-tests/1] has passed",
+                Some(("delorean-api.build", 7, "unit-tests", 1, StageResult::Passed))),
+            ("Pipeline stage [Zeus_ECS_Distro/20/Deploy] passed", None),
+            ("Pipeline stage [Zeus_ECS_Distro/20/Deploy/1] exploded",
+                Some(("Zeus_ECS_Distro", 20, "Deploy", 1, StageResult::Unknown))),
+            ("Live long and prospect.", None),
+        ];
+        for (title, expected) in cases {
+            let expected = expected.map(|(pipeline_name, pipeline_counter, stage_name, stage_counter, result)|
+                StageEvent {
+                    pipeline_name: pipeline_name.to_string(),
+                    pipeline_counter,
+                    stage_name: stage_name.to_string(),
+                    stage_counter,
+                    result,
+                    timestamp,
+                    details: StageDetails::default(),
+                });
+            assert_eq!(parse_stage_event(&title_regex, title, timestamp), expected, "Parsing '{}'", title);
+        }
+    }
+
+    #[test]
+    fn test_parse_stage_details() {
+        let change_regex = Regex::new(&change_regex_string()).unwrap();
+        let changes = "git: git@github.com:mdsol/delorean.git revision 8d1e2c4a, modified by Marty McFly \
+            <marty@example.com> on 2019-08-14 09:21:00.0\nFlux capacitor fix\n\
+            git: git@github.com:mdsol/delorean.git revision 77aa01ee, modified by Doc Brown on 2019-08-14 09:01:00.0";
+        let cases = vec![
+            (Some("https://gocd.example.com/go/pipelines/Delorean_Build/1432/Test/1"), None,
+                vec![("Triggered by", "changes"), ("Label", "1432"), ("Changes", changes)],
+                StageDetails {
+                    gocd_url: Some("https://gocd.example.com/go/pipelines/Delorean_Build/1432/Test/1".to_string()),
+                    triggered_by: Some("changes".to_string()),
+                    label: Some("1432".to_string()),
+                    changes: vec![
+                        Change { revision: "8d1e2c4a".to_string(), author: "Marty McFly <marty@example.com>".to_string() },
+                        Change { revision: "77aa01ee".to_string(), author: "Doc Brown".to_string() },
+                    ],
+                }),
+            (None, Some("Triggered by: Biff Tannen\nReason: Manual"), vec![],
+                StageDetails { triggered_by: Some("Biff Tannen".to_string()), ..StageDetails::default() }),
+            (Some("/go/pipelines"), Some("text"), vec![("Label", "")], StageDetails::default()),
+        ];
+        for (title_link, text, fields, expected) in cases {
+            assert_eq!(parse_stage_details(&change_regex, title_link, text, &fields), expected, "Parsing {:?} {:?}", title_link, text);
+        }
+    }
+
+    #[test]
+    fn test_stage_label() {
+        let title_regex = Regex::new(&title_regex_string()).unwrap();
+        let first_run = parse_stage_event(&title_regex, "Pipeline stage [Delorean_Deploy/88/Deploy/1] failed", Utc::now());
+        assert_eq!(first_run.unwrap().stage_label(), "Deploy");
+        let rerun = parse_stage_event(&title_regex, "Pipeline stage [Delorean_Deploy/88/Deploy/2] passed", Utc::now());
+        assert_eq!(rerun.unwrap().stage_label(), "Deploy (run 2)");
+    }
+
+    #[test]
+    fn test_parse_slack_timestamp() {
+        assert_eq!(parse_slack_timestamp("1355517523.000005"), Some(Utc.timestamp(1355517523, 0)));
+        assert_eq!(parse_slack_timestamp("yesterday"), None);
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+use std::thread;
+
+use chrono::prelude::*;
+use time::Duration;
+
+use crate::build_info_manager::BuildInfoManager;
+
+const TICK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+const DIGEST_HOUR_UTC: u32 = 13;
+const WEEKLY_DIGEST_DAY: Weekday = Weekday::Mon;
+const CLEANUP_INTERVAL_MINUTES: i64 = 10;
+
+pub enum DigestPeriod {
+    Daily,
+    Weekly,
+}
+
+impl DigestPeriod {
+    pub fn duration(&self) -> Duration {
+        match self {
+            DigestPeriod::Daily => Duration::days(1),
+            DigestPeriod::Weekly => Duration::weeks(1),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DigestPeriod::Daily => "Daily",
+            DigestPeriod::Weekly => "Weekly",
+        }
+    }
+}
+
+pub fn start(manager: Arc<BuildInfoManager>) {
+    thread::Builder::new()
+        .name("scheduler".to_string())
+        .spawn(move || {
+            let mut next_daily_digest = next_digest_time(Utc::now(), None);
+            let mut next_weekly_digest = next_digest_time(Utc::now(), Some(WEEKLY_DIGEST_DAY));
+            let mut next_cleanup = Utc::now() + Duration::minutes(CLEANUP_INTERVAL_MINUTES);
+            loop {
+                thread::sleep(TICK_INTERVAL);
+                let now = Utc::now();
+                manager.mark_stalled_builds();
+                manager.flush_quiet_hour_buffers();
+                manager.reconcile_fallback_builds();
+                if now >= next_cleanup {
+                    manager.clear_old_message_entries();
+                    next_cleanup = now + Duration::minutes(CLEANUP_INTERVAL_MINUTES);
+                }
+                if now >= next_daily_digest {
+                    manager.post_digests(DigestPeriod::Daily);
+                    next_daily_digest = next_digest_time(now, None);
+                }
+                if now >= next_weekly_digest {
+                    manager.post_digests(DigestPeriod::Weekly);
+                    next_weekly_digest = next_digest_time(now, Some(WEEKLY_DIGEST_DAY));
+                }
+            }
+        })
+        .expect("Unable to start scheduler thread");
+}
+
+//Works through the stage events the manager queues up from Slack, one at a time so the messages for a build are
+//updated in the order GoCD reported its stages
+pub fn start_event_worker(manager: Arc<BuildInfoManager>) {
+    let events = manager.take_event_receiver().expect("The event worker has already been started");
+    thread::Builder::new()
+        .name("event_worker".to_string())
+        .spawn(move || {
+            for event in events {
+                manager.handle_queued_event(&event);
+            }
+        })
+        .expect("Unable to start event worker thread");
+}
+
+fn next_digest_time(after: DateTime<Utc>, weekday: Option<Weekday>) -> DateTime<Utc> {
+    let mut candidate = after.date().and_hms(DIGEST_HOUR_UTC, 0, 0);
+    if candidate <= after {
+        candidate = candidate + Duration::days(1);
+    }
+    if let Some(day) = weekday {
+        while candidate.weekday() != day {
+            candidate = candidate + Duration::days(1);
+        }
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod scheduler_tests {
+    use super::*;
+
+    #[test]
+    fn test_next_digest_time() {
+        let before_digest_hour = Utc.ymd(2019, 8, 14).and_hms(9, 30, 0);
+        assert_eq!(next_digest_time(before_digest_hour, None), Utc.ymd(2019, 8, 14).and_hms(DIGEST_HOUR_UTC, 0, 0));
+        let after_digest_hour = Utc.ymd(2019, 8, 14).and_hms(DIGEST_HOUR_UTC, 0, 0);
+        assert_eq!(next_digest_time(after_digest_hour, None), Utc.ymd(2019, 8, 15).and_hms(DIGEST_HOUR_UTC, 0, 0));
+        assert_eq!(next_digest_time(after_digest_hour, Some(Weekday::Mon)),
+            Utc.ymd(2019, 8, 19).and_hms(DIGEST_HOUR_UTC, 0, 0));
+    }
+}
@@ -4,7 +4,7 @@ use serde_json::{Value, Map};
 use serde_derive::Deserialize;
 
 use rocket_contrib::json::Json;
-use rocket::request::Request;
+use rocket::request::{Request, FormItems};
 use rocket::outcome::Outcome::*;
 use rocket::data::{self, FromDataSimple};
 use rocket::Data;
@@ -17,6 +17,7 @@ use chrono::prelude::*;
 use time::Duration;
 
 use crate::build_info_manager::AcceptBuildInfo;
+use crate::parser::{parse_stage_event, parse_stage_details, parse_slack_timestamp};
 
 #[allow(dead_code)]
 pub struct SlackParams {
@@ -28,62 +29,109 @@ pub struct SlackParams {
     pub gocd_bod_id: String,
     pub instance_token: String,
     pub title_match_regex: Regex,
-    pub gocd_token: String,
+    pub change_match_regex: Regex,
 }
 
 pub struct VerifiedSlackJson {
     json_obj: Map<String, Value>,
+    //Which redelivery this is, when Slack is retrying an event it didn't see us acknowledge
+    retry_num: Option<u32>,
 }
 
 impl VerifiedSlackJson {
     pub fn json_obj(&self) -> &Map<String, Value> {
         &self.json_obj
     }
+
+    pub fn retry_num(&self) -> Option<u32> {
+        self.retry_num
+    }
+}
+
+//A slash command invocation, from the form Slack posts when someone uses one of our commands
+pub struct VerifiedSlackCommand {
+    pub user_id: String,
+    pub command: String,
+    pub text: String,
 }
 
 const LIMIT: u64 = 4000;
 
-impl FromDataSimple for VerifiedSlackJson {
-    type Error = String;
+//Checks Slack's signature on a request and hands back the raw body if it's good
+fn read_verified_body(request: &Request, data: Data) -> Result<String, (Status, String)> {
+    let header_map = request.headers();
+    let maybe_sig = header_map.get_one("X-Slack-Signature")
+        .and_then(|raw| raw.split('=').nth(1))
+        .and_then(|hex| hex::decode(hex).ok());
+    let maybe_ts = header_map.get_one("X-Slack-Request-Timestamp")
+        .and_then(|raw| raw.parse().ok());
+    if maybe_sig.is_none() || maybe_ts.is_none() {
+        return Err((Status::Unauthorized , "Missing Signature Headers!".to_string()));
+    }
 
-    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
-        let header_map = request.headers();
-        let maybe_sig = header_map.get_one("X-Slack-Signature")
-            .and_then(|raw| raw.split('=').nth(1))
-            .and_then(|hex| hex::decode(hex).ok());
-        let maybe_ts = header_map.get_one("X-Slack-Request-Timestamp")
-            .and_then(|raw| raw.parse().ok());
-        if maybe_sig.is_none() || maybe_ts.is_none() {
-            return Failure((Status::Unauthorized , "Missing Signature Headers!".to_string()));
-        }
+    let timestamp_diff = Utc.timestamp(maybe_ts.unwrap(), 0) - Utc::now();
+    if timestamp_diff > Duration::seconds(60) || timestamp_diff < Duration::seconds(-60) {
+        return Err((Status::Unauthorized , "Timestamp out of range".to_string()));
+    }
 
-        let timestamp_diff = Utc.timestamp(maybe_ts.unwrap(), 0) - Utc::now();
-        if timestamp_diff > Duration::seconds(60) || timestamp_diff < Duration::seconds(-60) {
-            return Failure((Status::Unauthorized , "Timestamp out of range".to_string()));
-        }
+    let mut raw_request = String::new();
+    if let Err(e) = data.open().take(LIMIT).read_to_string(&mut raw_request) {
+        return Err((Status::InternalServerError , format!("Some kind of badness: {}", e)));
+    }
 
-        let mut raw_request = String::new();
-        if let Err(e) = data.open().take(LIMIT).read_to_string(&mut raw_request) {
-            return Failure((Status::InternalServerError , format!("Some kind of badness: {}", e)));
-        }
+    let string_to_sign = format!("v0:{}:{}", &maybe_ts.unwrap(), &raw_request);
 
-        let string_to_sign = format!("v0:{}:{}", &maybe_ts.unwrap(), &raw_request);
+    //allow unwrap here because if there isn't a SlackParams state then something is fundamentally wrong and
+    //we should blow up
+    let verify_key = &request.guard::<rocket::State<SlackParams>>().unwrap().signing_secret;
+    let signature = maybe_sig.unwrap();
+    if let Err(e) = verify(&verify_key, string_to_sign.as_bytes(), &signature) {
+        return Err((Status::Unauthorized , format!("Failed to verify signature: {}", e)));
+    }
+    Ok(raw_request)
+}
 
-        //allow unwrap here because if there isn't a SlackParams state then something is fundamentally wrong and
-        //we should blow up
-        let verify_key = &request.guard::<rocket::State<SlackParams>>().unwrap().signing_secret;
-        let signature = maybe_sig.unwrap();
-        if let Err(e) = verify(&verify_key, string_to_sign.as_bytes(), &signature) {
-            return Failure((Status::Unauthorized , format!("Failed to verify signature: {}", e)));
-        }
+impl FromDataSimple for VerifiedSlackJson {
+    type Error = String;
 
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let retry_num = request.headers().get_one("X-Slack-Retry-Num").and_then(|raw| raw.parse().ok());
+        let raw_request = match read_verified_body(request, data) {
+            Ok(raw_request) => raw_request,
+            Err(failure) => return Failure(failure),
+        };
         match serde_json::from_str(&raw_request) {
-            Ok(Value::Object(json)) => Success(VerifiedSlackJson { json_obj: json }),
+            Ok(Value::Object(json)) => Success(VerifiedSlackJson { json_obj: json, retry_num }),
             _ => Failure((Status::BadRequest, "Unable to parse JSON".to_string())),
         }
     }
 }
 
+impl FromDataSimple for VerifiedSlackCommand {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let raw_request = match read_verified_body(request, data) {
+            Ok(raw_request) => raw_request,
+            Err(failure) => return Failure(failure),
+        };
+        let mut fields = std::collections::HashMap::new();
+        for item in FormItems::from(raw_request.as_str()) {
+            if let Ok(value) = item.value.url_decode() {
+                fields.insert(item.key.as_str().to_string(), value);
+            }
+        }
+        match (fields.remove("user_id"), fields.remove("command")) {
+            (Some(user_id), Some(command)) => Success(VerifiedSlackCommand {
+                user_id,
+                command,
+                text: fields.remove("text").unwrap_or_default(),
+            }),
+            _ => Failure((Status::BadRequest, "Missing command fields".to_string())),
+        }
+    }
+}
+
 #[derive(Deserialize)]
 #[allow(dead_code)]
 struct Message {
@@ -107,8 +155,16 @@ struct Attachment {
     color: Option<String>,
     id: Option<u64>,
     title: Option<String>,
+    title_link: Option<String>,
     text: Option<String>,
     fallback: Option<String>,
+    fields: Option<Vec<AttachmentField>>,
+}
+
+#[derive(Deserialize)]
+struct AttachmentField {
+    title: String,
+    value: String,
 }
 
 pub fn handle_event_object(event: &serde_json::map::Map<String, Value>, params: &SlackParams, collector: &dyn AcceptBuildInfo) -> Result<Json<Value>, String> {
@@ -117,15 +173,15 @@ pub fn handle_event_object(event: &serde_json::map::Map<String, Value>, params:
             match serde_json::from_value::<Message>(Value::Object(event.clone())) {
                 Err(err) => Err(format!("Failed to parse message into expected struct: {}", err)),
                 Ok(message) => {
+                    if message.channel_type == "im" && message.bot_id.is_none() {
+                        if let (Some(user), Some(text)) = (&message.user, &message.text) {
+                            process_direct_message(user, text, collector);
+                        }
+                    }
                     if message.bot_id.is_some() && message.bot_id.unwrap() == params.gocd_bod_id {
                         if let Some(attachments) = message.attachments {
-                            if let Some(first_attachment) = attachments.first() {
-                                info!("Got attachments with title {:?} and text {:?}",
-                                    first_attachment.title, first_attachment.text);
-                                if let Some(title) = &first_attachment.title {
-                                    process_message(&title, &params, collector);
-                                }
-                            }
+                            let timestamp = parse_slack_timestamp(&message.ts).unwrap_or_else(Utc::now);
+                            process_attachments(&attachments, timestamp, &params, collector);
                         }
                     }
                     Ok(Json(Value::Null))
@@ -137,26 +193,31 @@ pub fn handle_event_object(event: &serde_json::map::Map<String, Value>, params:
     }
 }
 
-pub fn get_regex_string() -> String {
-    r"^Pipeline stage \[(?P<stage_name>[\w_]+)/(?P<build_num>\d+)/(?P<step_name>\w+)/\d+\] (?P<pass_fail>passed|failed)".to_string()
+//People can DM the bot to turn failure notifications for their commits off and back on
+fn process_direct_message(user: &str, text: &str, collector: &dyn AcceptBuildInfo) {
+    match text.trim().to_lowercase().as_str() {
+        "stop" => collector.set_author_dm_opt_out(user, true),
+        "start" => collector.set_author_dm_opt_out(user, false),
+        _ => info!("Ignoring direct message from {}", user),
+    }
 }
 
-fn process_message(message_text: &str, params: &SlackParams, collector: &dyn AcceptBuildInfo) {
-    match params.title_match_regex.captures(message_text) {
-        None => info!("Unable to handle message '{}' with regex", message_text),
-        Some(captures) => {
-            let stage_name = captures.name("stage_name");
-            let build_num = captures.name("build_num").and_then(|m| m.as_str().parse().ok());
-            let step_name = captures.name("step_name");
-            let pass_fail = captures.name("pass_fail");
-            if stage_name.is_some() && build_num.is_some() && step_name.is_some() && pass_fail.is_some() {
-                collector.new_build_message(
-                    stage_name.unwrap().as_str(),
-                    build_num.unwrap(),
-                    step_name.unwrap().as_str(),
-                    pass_fail.unwrap().as_str()
-                );
-            }
+//The stage is in the title of one of the attachments, or in the fallback if the notifier didn't set a title
+fn process_attachments(attachments: &[Attachment], timestamp: DateTime<Utc>, params: &SlackParams,
+                       collector: &dyn AcceptBuildInfo) {
+    for attachment in attachments {
+        info!("Got attachment with title {:?} and text {:?}", attachment.title, attachment.text);
+        let titles = attachment.title.iter().chain(attachment.fallback.iter());
+        let maybe_event = titles.filter_map(|title| parse_stage_event(&params.title_match_regex, title, timestamp))
+            .next();
+        if let Some(mut event) = maybe_event {
+            let fields: Vec<(&str, &str)> = attachment.fields.iter().flatten()
+                .map(|field| (field.title.as_str(), field.value.as_str()))
+                .collect();
+            event.details = parse_stage_details(&params.change_match_regex, attachment.title_link.as_deref(), attachment.text.as_deref(), &fields);
+            collector.new_build_message(&event);
+            return;
         }
     }
+    info!("Unable to find a stage in {} attachments", attachments.len());
 }
@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::sync::Mutex;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::parser::StageResult;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SubscriptionEvents {
+    All,
+    Failures,
+    Passes,
+}
+
+impl SubscriptionEvents {
+    pub fn name(self) -> &'static str {
+        match self {
+            SubscriptionEvents::All => "all",
+            SubscriptionEvents::Failures => "failures",
+            SubscriptionEvents::Passes => "passes",
+        }
+    }
+
+    fn matches(self, result: StageResult) -> bool {
+        match self {
+            SubscriptionEvents::All => true,
+            SubscriptionEvents::Failures => result == StageResult::Failed,
+            SubscriptionEvents::Passes => result == StageResult::Passed,
+        }
+    }
+}
+
+//Target is either a monitor name or the name of a single pipeline within one
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub user_id: String,
+    pub target: String,
+    pub events: SubscriptionEvents,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StoreData {
+    #[serde(default)]
+    author_dm_opt_outs: BTreeSet<String>,
+    #[serde(default)]
+    subscriptions: Vec<Subscription>,
+    //Monitor name to the timestamp of its pinned status message, so a restart updates the same pin
+    #[serde(default)]
+    pinned_status_messages: BTreeMap<String, String>,
+}
+
+//Settings that users change through Slack, and the messages the bot has to find again, kept in a JSON file so they
+//survive restarts. Every change rewrites the whole file, which is fine at the size this gets to.
+pub struct BotStore {
+    path: Option<String>,
+    data: Mutex<StoreData>,
+}
+
+impl BotStore {
+    pub fn load(path: &str) -> Result<BotStore, String> {
+        let data = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("Unable to parse store file {}: {}", path, e))?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => StoreData::default(),
+            Err(e) => return Err(format!("Unable to read store file {}: {}", path, e)),
+        };
+        Ok(BotStore { path: Some(path.to_string()), data: Mutex::new(data) })
+    }
+
+    //For when the store file can't be used, so nothing gets saved over it
+    pub fn in_memory() -> BotStore {
+        BotStore { path: None, data: Mutex::new(StoreData::default()) }
+    }
+
+    fn save(&self, data: &StoreData) {
+        if let Some(path) = &self.path {
+            let result = serde_json::to_string_pretty(data).map_err(|e| e.to_string())
+                .and_then(|contents| fs::write(path, contents).map_err(|e| e.to_string()));
+            if let Err(error) = result {
+                error!("Unable to save store file {}: {}", path, error);
+            }
+        }
+    }
+
+    pub fn is_author_dm_opted_out(&self, user_id: &str) -> bool {
+        self.data.lock().unwrap().author_dm_opt_outs.contains(user_id)
+    }
+
+    pub fn set_author_dm_opt_out(&self, user_id: &str, opted_out: bool) {
+        let mut data = self.data.lock().unwrap();
+        if opted_out {
+            data.author_dm_opt_outs.insert(user_id.to_string());
+        } else {
+            data.author_dm_opt_outs.remove(user_id);
+        }
+        self.save(&data);
+    }
+
+    pub fn pinned_status_message(&self, monitor_name: &str) -> Option<String> {
+        self.data.lock().unwrap().pinned_status_messages.get(monitor_name).cloned()
+    }
+
+    pub fn set_pinned_status_message(&self, monitor_name: &str, slack_timestamp: &str) {
+        let mut data = self.data.lock().unwrap();
+        data.pinned_status_messages.insert(monitor_name.to_string(), slack_timestamp.to_string());
+        self.save(&data);
+    }
+
+    //Subscribing to the same thing again replaces the events rather than adding a second subscription
+    pub fn subscribe(&self, user_id: &str, target: &str, events: SubscriptionEvents) {
+        let mut data = self.data.lock().unwrap();
+        data.subscriptions.retain(|s| !(s.user_id == user_id && s.target.eq_ignore_ascii_case(target)));
+        data.subscriptions.push(Subscription { user_id: user_id.to_string(), target: target.to_string(), events });
+        self.save(&data);
+    }
+
+    pub fn unsubscribe(&self, user_id: &str, target: &str) -> bool {
+        let mut data = self.data.lock().unwrap();
+        let before = data.subscriptions.len();
+        data.subscriptions.retain(|s| !(s.user_id == user_id && s.target.eq_ignore_ascii_case(target)));
+        let removed = data.subscriptions.len() != before;
+        if removed {
+            self.save(&data);
+        }
+        removed
+    }
+
+    pub fn subscriptions_for_user(&self, user_id: &str) -> Vec<Subscription> {
+        self.data.lock().unwrap().subscriptions.iter().filter(|s| s.user_id == user_id).cloned().collect()
+    }
+
+    pub fn subscribers_for(&self, monitor_name: &str, pipeline_name: &str, result: StageResult) -> Vec<String> {
+        let mut user_ids: Vec<String> = self.data.lock().unwrap().subscriptions.iter()
+            .filter(|s| s.target.eq_ignore_ascii_case(monitor_name) || s.target.eq_ignore_ascii_case(pipeline_name))
+            .filter(|s| s.events.matches(result))
+            .map(|s| s.user_id.clone())
+            .collect();
+        user_ids.sort();
+        user_ids.dedup();
+        user_ids
+    }
+}
+
+#[cfg(test)]
+mod store_tests {
+    use super::*;
+
+    #[test]
+    fn test_persists_opt_outs() {
+        let path = std::env::temp_dir().join(format!("bot_store_test_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let store = BotStore::load(path).unwrap();
+        assert!(!store.is_author_dm_opted_out("U123"));
+        store.set_author_dm_opt_out("U123", true);
+        store.set_author_dm_opt_out("U456", true);
+        store.set_author_dm_opt_out("U456", false);
+
+        let reloaded = BotStore::load(path).unwrap();
+        assert!(reloaded.is_author_dm_opted_out("U123"));
+        assert!(!reloaded.is_author_dm_opted_out("U456"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_persists_pinned_status_messages() {
+        let path = std::env::temp_dir().join(format!("bot_store_pins_test_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let store = BotStore::load(path).unwrap();
+        assert_eq!(store.pinned_status_message("Delorean"), None);
+        store.set_pinned_status_message("Delorean", "1355517523.000005");
+        store.set_pinned_status_message("Zeus", "1355517524.000001");
+        store.set_pinned_status_message("Zeus", "1355517600.000002");
+
+        let reloaded = BotStore::load(path).unwrap();
+        assert_eq!(reloaded.pinned_status_message("Delorean"), Some("1355517523.000005".to_string()));
+        assert_eq!(reloaded.pinned_status_message("Zeus"), Some("1355517600.000002".to_string()));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_subscribers_for() {
+        let store = BotStore::in_memory();
+        store.subscribe("U1", "Delorean", SubscriptionEvents::Failures);
+        store.subscribe("U2", "delorean_build", SubscriptionEvents::All);
+        store.subscribe("U3", "Delorean", SubscriptionEvents::Passes);
+        store.subscribe("U3", "Delorean", SubscriptionEvents::All);
+        store.subscribe("U4", "Zeus", SubscriptionEvents::All);
+        assert_eq!(store.subscribers_for("Delorean", "Delorean_Build", StageResult::Failed), vec!["U1", "U2", "U3"]);
+        assert_eq!(store.subscribers_for("Delorean", "Delorean_Deploy", StageResult::Passed), vec!["U3"]);
+        assert_eq!(store.subscribers_for("Delorean", "Delorean_Build", StageResult::Cancelled), vec!["U2", "U3"]);
+        assert_eq!(store.subscriptions_for_user("U3").len(), 1);
+        assert!(store.unsubscribe("U1", "DELOREAN"));
+        assert!(!store.unsubscribe("U1", "Delorean"));
+        assert_eq!(store.subscribers_for("Delorean", "Delorean_Build", StageResult::Failed), vec!["U2", "U3"]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use serde_derive::Deserialize;
+
+pub const TEMPLATE_VARIABLES: &[&str] = &[
+    "monitor", "stage", "step", "result", "counter", "revision", "author", "triggered_by", "duration", "lead_time", "link",
+    "notes",
+];
+
+enum TemplatePart {
+    Text(String),
+    Variable(&'static str),
+}
+
+//A message with {{variable}} placeholders, checked against TEMPLATE_VARIABLES when it's parsed so that typos show
+//up at startup rather than in a Slack channel
+pub struct Template {
+    parts: Vec<TemplatePart>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, String> {
+        let mut parts = vec![];
+        let mut remaining = source;
+        while let Some(start) = remaining.find("{{") {
+            if start > 0 {
+                parts.push(TemplatePart::Text(remaining[..start].to_string()));
+            }
+            let after_start = &remaining[start + 2..];
+            let end = after_start.find("}}")
+                .ok_or_else(|| format!("Unclosed '{{{{' at position {}", source.len() - remaining.len() + start))?;
+            let name = after_start[..end].trim();
+            let variable = TEMPLATE_VARIABLES.iter().find(|v| **v == name)
+                .ok_or_else(|| format!("Unknown variable '{}', expected one of {}", name, TEMPLATE_VARIABLES.join(", ")))?;
+            parts.push(TemplatePart::Variable(variable));
+            remaining = &after_start[end + 2..];
+        }
+        if !remaining.is_empty() {
+            parts.push(TemplatePart::Text(remaining.to_string()));
+        }
+        Ok(Template { parts })
+    }
+
+    pub fn render(&self, values: &HashMap<&str, String>) -> String {
+        self.parts.iter()
+            .map(|part| match part {
+                TemplatePart::Text(text) => text.as_str(),
+                TemplatePart::Variable(name) => values.get(name).map(|v| v.as_str()).unwrap_or(""),
+            })
+            .collect()
+    }
+}
+
+pub struct MessageTemplates {
+    pub started: Template,
+    pub passed: Template,
+    pub failed: Template,
+    pub completed: Template,
+    pub cancelled: Template,
+}
+
+const DEFAULT_TEMPLATE: &str = "GoCD Build for {{monitor}} has reached step {{step}} on {{stage}} and {{result}}{{notes}}";
+const DEFAULT_CANCELLED_TEMPLATE: &str =
+    "GoCD Build for {{monitor}} had step {{step}} on {{stage}} :no_entry_sign: cancelled{{notes}}";
+
+//A monitor's templates as they're written in its config, not yet parsed. Any left out are the defaults.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateConfig {
+    #[serde(default = "default_template")]
+    pub started: String,
+    #[serde(default = "default_template")]
+    pub passed: String,
+    #[serde(default = "default_template")]
+    pub failed: String,
+    #[serde(default = "default_template")]
+    pub completed: String,
+    #[serde(default = "default_cancelled_template")]
+    pub cancelled: String,
+}
+
+fn default_template() -> String {
+    DEFAULT_TEMPLATE.to_string()
+}
+
+fn default_cancelled_template() -> String {
+    DEFAULT_CANCELLED_TEMPLATE.to_string()
+}
+
+impl MessageTemplates {
+    pub fn new(started: &str, passed: &str, failed: &str, completed: &str, cancelled: &str)
+    -> Result<MessageTemplates, String> {
+        let parse = |name: &str, source: &str| Template::parse(source)
+            .map_err(|e| format!("Invalid '{}' template '{}': {}", name, source, e));
+        Ok(MessageTemplates {
+            started: parse("started", started)?,
+            passed: parse("passed", passed)?,
+            failed: parse("failed", failed)?,
+            completed: parse("completed", completed)?,
+            cancelled: parse("cancelled", cancelled)?,
+        })
+    }
+
+    pub fn default_templates() -> MessageTemplates {
+        MessageTemplates::new(DEFAULT_TEMPLATE, DEFAULT_TEMPLATE, DEFAULT_TEMPLATE, DEFAULT_TEMPLATE,
+            DEFAULT_CANCELLED_TEMPLATE).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod template_tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let template = Template::parse("Build {{ counter }} of {{stage}} {{result}}!").unwrap();
+        let mut values = HashMap::new();
+        values.insert("counter", "20".to_string());
+        values.insert("stage", "Zeus_ECS_Distro".to_string());
+        assert_eq!(template.render(&values), "Build 20 of Zeus_ECS_Distro !");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let cases = vec![
+            ("Build {{counter", "Unclosed '{{' at position 6"),
+            ("Build {{build_num}}", "Unknown variable 'build_num'"),
+        ];
+        for (source, expected_error) in cases {
+            let error = Template::parse(source).err().expect(source);
+            assert!(error.starts_with(expected_error), "Got error '{}' for '{}'", error, source);
+        }
+        let error = MessageTemplates::new(DEFAULT_TEMPLATE, DEFAULT_TEMPLATE, "{{oops}}", DEFAULT_TEMPLATE,
+            DEFAULT_CANCELLED_TEMPLATE).err().unwrap();
+        assert!(error.starts_with("Invalid 'failed' template '{{oops}}'"));
+    }
+}
@@ -1,23 +1,33 @@
 use serde_json::json;
 use crate::slack::{SlackParams, handle_event_object};
 use crate::build_info_manager::AcceptBuildInfo;
+use crate::parser::{StageEvent, StageResult, StageDetails};
 use std::cell::RefCell;
 
 struct DummyBuildInfoAcceptor {
-    builds_received: RefCell<Vec<(String, u64)>>,
+    builds_received: RefCell<Vec<(String, u64, StageResult)>>,
+    details_received: RefCell<Vec<StageDetails>>,
+    opt_outs_received: RefCell<Vec<(String, bool)>>,
 }
 
 impl DummyBuildInfoAcceptor {
     fn new() -> DummyBuildInfoAcceptor {
         DummyBuildInfoAcceptor {
             builds_received: RefCell::new(vec![]),
+            details_received: RefCell::new(vec![]),
+            opt_outs_received: RefCell::new(vec![]),
         }
     }
 }
 
 impl AcceptBuildInfo for DummyBuildInfoAcceptor {
-    fn new_build_message(&self, stage_name: &str, build_num: u64, _build_step: &str, _pass_fail: &str) {
-        self.builds_received.borrow_mut().push((stage_name.to_string(), build_num));
+    fn new_build_message(&self, event: &StageEvent) {
+        self.builds_received.borrow_mut().push((event.pipeline_name.clone(), event.pipeline_counter, event.result));
+        self.details_received.borrow_mut().push(event.details.clone());
+    }
+
+    fn set_author_dm_opt_out(&self, user_id: &str, opted_out: bool) {
+        self.opt_outs_received.borrow_mut().push((user_id.to_string(), opted_out));
     }
 }
 
@@ -93,4 +103,62 @@ fn handle_gocd_build_message() {
     let info_result = builds_received_vec.first().expect("Did not receive an item");
     assert_eq!(info_result.0, "Zeus_ECS_Distro");
     assert_eq!(info_result.1, 20);
+    assert_eq!(info_result.2, StageResult::Passed);
+}
+
+#[test]
+fn handle_direct_message_opt_out() {
+    let dummy_params = SlackParams::from_env(false);
+    let build_info = DummyBuildInfoAcceptor::new();
+    for text in &["STOP ", "what's this?", "start"] {
+        let event = json!({
+            "type": "message",
+            "channel": "D024BE91L",
+            "user": "U2147483697",
+            "text": text,
+            "ts": "1355517523.000005",
+            "channel_type": "im"
+        });
+        let result = handle_event_object(event.as_object().unwrap(), &dummy_params, &build_info);
+        assert!(result.is_ok(), "Error is: {:?}", result.err().unwrap());
+    }
+    assert_eq!(*build_info.opt_outs_received.borrow(),
+        vec![("U2147483697".to_string(), true), ("U2147483697".to_string(), false)]);
+}
+
+#[test]
+fn handle_gocd_build_message_in_later_attachment() {
+    let dummy_params = SlackParams::from_env(false);
+    let event = json!({
+        "type": "message",
+        "bot_id": dummy_params.gocd_bod_id,
+        "channel": "C024BE91L",
+        "channel_type": "channel",
+        "ts": "1355517523.000005",
+        "attachments": [
+            {
+                "id": 1,
+                "text": "Something unrelated"
+            },
+            {
+                "id": 2,
+                "fallback": "Stage [Zeus_ECS_Distro/21/Deploy/1] was cancelled",
+                "title_link": "https://gocd.example.com/go/pipelines/Zeus_ECS_Distro/21/Deploy/1",
+                "text": "Triggered by: Marty McFly",
+                "fields": [
+                    {"title": "Label", "value": "21", "short": true}
+                ]
+            },
+        ],
+    });
+    let build_info = DummyBuildInfoAcceptor::new();
+    let result = handle_event_object(event.as_object().unwrap(), &dummy_params, &build_info);
+    assert!(result.is_ok(), "Error is: {:?}", result.err().unwrap());
+    assert_eq!(*build_info.builds_received.borrow(), vec![("Zeus_ECS_Distro".to_string(), 21, StageResult::Cancelled)]);
+    assert_eq!(*build_info.details_received.borrow(), vec![StageDetails {
+        gocd_url: Some("https://gocd.example.com/go/pipelines/Zeus_ECS_Distro/21/Deploy/1".to_string()),
+        triggered_by: Some("Marty McFly".to_string()),
+        label: Some("21".to_string()),
+        changes: vec![],
+    }]);
 }
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+
+//Works out which Slack user made a commit from the email on it. Addresses in the mapping file win, since plenty of
+//people commit with a different address from the one on their Slack account; anything else goes to
+//users.lookupByEmail, with the answer cached either way so a failing build doesn't mean a lookup per stage.
+pub struct UserDirectory {
+    static_mapping: HashMap<String, String>,
+    lookup_cache: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl UserDirectory {
+    pub fn new(static_mapping: HashMap<String, String>) -> UserDirectory {
+        UserDirectory {
+            static_mapping: static_mapping.into_iter().map(|(email, user_id)| (email.to_lowercase(), user_id)).collect(),
+            lookup_cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    //The mapping file is a JSON object of email address to Slack user id
+    pub fn load(mapping_path: Option<String>) -> Result<UserDirectory, String> {
+        let static_mapping = match mapping_path {
+            Some(path) => {
+                let contents = fs::read_to_string(&path)
+                    .map_err(|e| format!("Unable to read user mapping file {}: {}", path, e))?;
+                serde_json::from_str(&contents).map_err(|e| format!("Unable to parse user mapping file {}: {}", path, e))?
+            },
+            None => HashMap::new(),
+        };
+        Ok(UserDirectory::new(static_mapping))
+    }
+
+    pub fn slack_user_for_email(&self, email: &str, lookup: impl FnOnce(&str) -> Result<Option<String>, String>)
+    -> Option<String> {
+        let email = email.to_lowercase();
+        if let Some(user_id) = self.static_mapping.get(&email) {
+            return Some(user_id.clone());
+        }
+        if let Some(cached) = self.lookup_cache.lock().unwrap().get(&email) {
+            return cached.clone();
+        }
+        match lookup(&email) {
+            Ok(user_id) => {
+                self.lookup_cache.lock().unwrap().insert(email, user_id.clone());
+                user_id
+            },
+            Err(error) => {
+                error!("Unable to look up Slack user for {}: {}", email, error);
+                None
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod user_directory_tests {
+    use super::*;
+
+    #[test]
+    fn test_slack_user_for_email() {
+        let mut mapping = HashMap::new();
+        mapping.insert("Marty@Example.com".to_string(), "U111".to_string());
+        let directory = UserDirectory::new(mapping);
+        assert_eq!(directory.slack_user_for_email("marty@example.com", |_| panic!("Should use mapping")),
+            Some("U111".to_string()));
+
+        assert_eq!(directory.slack_user_for_email("doc@example.com", |_| Ok(Some("U222".to_string()))),
+            Some("U222".to_string()));
+        assert_eq!(directory.slack_user_for_email("DOC@example.com", |_| panic!("Should be cached")),
+            Some("U222".to_string()));
+
+        assert_eq!(directory.slack_user_for_email("biff@example.com", |_| Err("ratelimited".to_string())), None);
+        assert_eq!(directory.slack_user_for_email("biff@example.com", |_| Ok(None)), None);
+        assert_eq!(directory.slack_user_for_email("biff@example.com", |_| panic!("Should be cached")), None);
+    }
+}